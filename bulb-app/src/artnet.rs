@@ -0,0 +1,114 @@
+use crate::{send, Color, Command};
+use anyhow::{anyhow, Result};
+use bulb_core::rgb_to_hsv;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// Consoles resend every universe at up to 44Hz, far faster than a bulb takes
+/// commands, so only the latest values are forwarded this often
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+const HEADER: &[u8; 8] = b"Art-Net\0";
+const OP_DMX: u16 = 0x5000;
+
+/// Where the fixture is patched
+#[derive(Debug, Clone, Copy)]
+pub struct Patch {
+    /// 15 bit port address: net, sub-net and universe
+    pub universe: u16,
+    /// First channel, 1-512
+    pub address: u16,
+    /// Use a fourth channel after blue as a dimmer
+    pub dimmer: bool,
+}
+
+impl Patch {
+    fn channels(&self) -> usize {
+        if self.dimmer {
+            4
+        } else {
+            3
+        }
+    }
+}
+
+/// Listen for Art-Net DMX and drive every bulb like an RGB(+dimmer) fixture
+///
+/// Black, or the dimmer at zero, turns the bulbs off. Changes are forwarded
+/// at most every `DEBOUNCE`, always with the latest values received.
+pub async fn serve(addr: SocketAddr, patch: Patch, tx: mpsc::UnboundedSender<Command>, bulb_count: usize) -> Result<()> {
+    if patch.address == 0 || patch.address as usize + patch.channels() - 1 > 512 {
+        return Err(anyhow!(
+            "Art-Net address {} doesn't leave room for {} channels (1-512)",
+            patch.address,
+            patch.channels()
+        ));
+    }
+
+    let socket = UdpSocket::bind(addr).await?;
+    println!("Art-Net listening on {} for universe {} at address {}", addr, patch.universe, patch.address);
+
+    let mut buf = [0u8; 1024];
+    let mut latest = None;
+    let mut sent = None;
+    let mut ticker = tokio::time::interval(DEBOUNCE);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, _) = received?;
+                if let Some(values) = fixture_values(&buf[..len], patch) {
+                    latest = Some(values);
+                }
+            }
+            _ = ticker.tick() => {
+                if let Some(values) = latest.filter(|values| Some(*values) != sent) {
+                    forward(values, &tx, bulb_count);
+                    sent = Some(values);
+                }
+            }
+        }
+    }
+}
+
+/// The fixture's channels from an ArtDmx packet for our universe, RGB and dimmer
+fn fixture_values(packet: &[u8], patch: Patch) -> Option<[u8; 4]> {
+    if packet.get(..8)? != HEADER || u16::from_le_bytes([*packet.get(8)?, *packet.get(9)?]) != OP_DMX {
+        return None;
+    }
+
+    let universe = u16::from_le_bytes([*packet.get(14)?, *packet.get(15)?]) & 0x7fff;
+    if universe != patch.universe {
+        return None;
+    }
+
+    let len = u16::from_be_bytes([*packet.get(16)?, *packet.get(17)?]) as usize;
+    let data = packet.get(18..18 + len)?;
+    // consoles may send fewer channels than a full universe, missing ones are 0
+    let channel = |offset: usize| data.get(patch.address as usize - 1 + offset).copied().unwrap_or(0);
+
+    let dimmer = if patch.dimmer { channel(3) } else { 255 };
+    Some([channel(0), channel(1), channel(2), dimmer])
+}
+
+fn forward([r, g, b, dimmer]: [u8; 4], tx: &mpsc::UnboundedSender<Command>, bulb_count: usize) {
+    let (hue, saturation, brightness) = rgb_to_hsv(r, g, b);
+    let brightness = (brightness as u32 * dimmer as u32 / 255) as u16;
+
+    if brightness == 0 {
+        send(tx, Command::Power(false));
+        return;
+    }
+
+    let color = Color {
+        hue,
+        saturation,
+        brightness,
+    };
+    for index in 0..bulb_count {
+        send(tx, Command::Color(index, color));
+    }
+}
@@ -0,0 +1,182 @@
+use anyhow::{anyhow, Result};
+use crate::midi::MidiChannel;
+use crate::mqtt::MqttConfig;
+use bulb_core::BulbConfig;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::Path;
+
+/// The part of the color a CC controls
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorParam {
+    Hue,
+    Saturation,
+    Brightness,
+}
+
+/// A CC and the bulbs it drives, as indices into the group
+#[derive(Debug, Clone)]
+pub struct CcRoute {
+    pub cc: u8,
+    pub param: ColorParam,
+    pub bulbs: Vec<usize>,
+}
+
+/// A color recalled by Program Change, the program number is its position in the list
+#[derive(Debug, Clone, Deserialize)]
+pub struct ColorPreset {
+    pub name: Option<String>,
+    pub hue: u16,
+    pub saturation: u16,
+    pub brightness: u16,
+}
+
+/// Everything the app runs with
+pub struct AppConfig {
+    pub bulbs: Vec<BulbConfig>,
+    /// Empty if the config file didn't have a mapping, the CC flags are used then
+    pub routes: Vec<CcRoute>,
+    pub midi_channel: Option<MidiChannel>,
+    pub presets: Vec<ColorPreset>,
+    /// Set to bridge the bulbs to Home Assistant
+    pub mqtt: Option<MqttConfig>,
+}
+
+impl AppConfig {
+    /// Load either a single `[bulb]` file or a multi-bulb file. Both can
+    /// set a top level `midi_channel`, 1-16 or "omni", list `[[presets]]`
+    /// for Program Change to recall, and have an `[mqtt]` broker (see
+    /// `MqttConfig`).
+    ///
+    /// ```toml
+    /// midi_channel = 1
+    ///
+    /// # program 0
+    /// [[presets]]
+    /// name = "verse"
+    /// hue = 240
+    /// saturation = 1000
+    /// brightness = 600
+    ///
+    /// [[bulbs]]
+    /// name = "left"
+    /// device_id = "..."
+    /// local_key = "..."
+    /// ip = "192.168.1.50"
+    ///
+    /// [[bulbs]]
+    /// name = "right"
+    /// # ...
+    ///
+    /// # bulbs can be left out to drive all of them
+    /// [[mapping]]
+    /// cc = 1
+    /// param = "hue"
+    /// bulbs = ["left"]
+    /// ```
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let table: toml::Table = contents.parse()?;
+
+        let midi_channel = match table.get("midi_channel") {
+            None => None,
+            Some(toml::Value::Integer(channel)) => Some(channel.to_string().parse().map_err(|e: String| anyhow!(e))?),
+            Some(toml::Value::String(channel)) => Some(channel.parse().map_err(|e: String| anyhow!(e))?),
+            Some(other) => return Err(anyhow!("midi_channel should be 1-16 or \"omni\", not {}", other)),
+        };
+
+        #[derive(Deserialize)]
+        struct Shared {
+            #[serde(default)]
+            presets: Vec<ColorPreset>,
+            mqtt: Option<MqttConfig>,
+        }
+
+        let Shared { presets, mqtt } = toml::from_str(&contents)?;
+        for (program, preset) in presets.iter().enumerate() {
+            if preset.hue > 360 || preset.saturation > 1000 || preset.brightness > 1000 {
+                return Err(anyhow!(
+                    "Preset {} is out of range, hue is 0-360 and saturation and brightness 0-1000",
+                    preset.name.as_deref().map_or(program.to_string(), |name| format!("\"{}\"", name))
+                ));
+            }
+        }
+
+        if !table.contains_key("bulbs") {
+            return Ok(Self {
+                bulbs: vec![BulbConfig::from_toml_str(&contents)?],
+                routes: Vec::new(),
+                midi_channel,
+                presets,
+                mqtt,
+            });
+        }
+
+        let bulbs = BulbConfig::list_from_toml_str(&contents)?;
+        if bulbs.is_empty() {
+            return Err(anyhow!("{} doesn't define any bulbs", path.display()));
+        }
+
+        let mut names = HashSet::new();
+        for bulb in &bulbs {
+            let name = bulb
+                .name
+                .as_deref()
+                .ok_or_else(|| anyhow!("Bulb {} needs a name when there are several bulbs", bulb.device_id))?;
+            if !names.insert(name) {
+                return Err(anyhow!("There's more than one bulb named \"{}\"", name));
+            }
+        }
+
+        #[derive(Deserialize)]
+        struct File {
+            #[serde(default)]
+            mapping: Vec<Mapping>,
+        }
+
+        #[derive(Deserialize)]
+        struct Mapping {
+            cc: u8,
+            param: ColorParam,
+            #[serde(default)]
+            bulbs: Vec<String>,
+        }
+
+        let file: File = toml::from_str(&contents)?;
+        let routes = file
+            .mapping
+            .into_iter()
+            .map(|mapping| {
+                let indices = if mapping.bulbs.is_empty() {
+                    (0..bulbs.len()).collect()
+                } else {
+                    mapping
+                        .bulbs
+                        .iter()
+                        .map(|name| {
+                            bulbs.iter().position(|bulb| bulb.name.as_deref() == Some(name)).ok_or_else(|| {
+                                anyhow!("The mapping for CC {} refers to an unknown bulb \"{}\"", mapping.cc, name)
+                            })
+                        })
+                        .collect::<Result<_>>()?
+                };
+
+                Ok(CcRoute {
+                    cc: mapping.cc,
+                    param: mapping.param,
+                    bulbs: indices,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            bulbs,
+            routes,
+            midi_channel,
+            presets,
+            mqtt,
+        })
+    }
+}
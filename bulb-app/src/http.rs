@@ -0,0 +1,226 @@
+use anyhow::Result;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{FromRef, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use bulb_core::{prometheus_text, BulbError, BulbGroup, BulbState};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+
+/// How often the cached bulb states are checked for changes to push to `/ws`
+const STATE_POLL: Duration = Duration::from_millis(250);
+
+type SharedGroup = Arc<Mutex<BulbGroup>>;
+
+#[derive(Clone)]
+struct AppState {
+    group: SharedGroup,
+    /// The latest `{"bulbs": [...]}`, updated by `watch_states`
+    states: watch::Receiver<Value>,
+}
+
+impl FromRef<AppState> for SharedGroup {
+    fn from_ref(app: &AppState) -> Self {
+        app.group.clone()
+    }
+}
+
+impl FromRef<AppState> for watch::Receiver<Value> {
+    fn from_ref(app: &AppState) -> Self {
+        app.states.clone()
+    }
+}
+
+#[derive(Deserialize)]
+struct ColorRequest {
+    h: u16,
+    s: u16,
+    v: u16,
+    #[serde(default = "default_immediate")]
+    immediate: bool,
+}
+
+fn default_immediate() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct PowerRequest {
+    on: bool,
+}
+
+/// Serve a small REST API for every bulb in the group
+///
+/// - `POST /color` with `{"h": 0-360, "s": 0-1000, "v": 0-1000, "immediate": true}`,
+///   which leaves the power alone
+/// - `POST /power` with `{"on": true}`
+/// - `GET /state` returns the last known state of each bulb, without querying them
+/// - `GET /metrics` returns each bulb's command counters for Prometheus
+/// - `GET /ws` is a WebSocket that pushes the `/state` JSON on connect and
+///   whenever a bulb's state changes
+///
+/// Commands share the group with the MIDI and OSC inputs, so they go through
+/// the same retries.
+pub async fn serve(addr: SocketAddr, group: SharedGroup) -> Result<()> {
+    let (states_tx, states) = watch::channel(json!({ "bulbs": [] }));
+    tokio::spawn(watch_states(group.clone(), states_tx));
+
+    let app = Router::new()
+        .route("/color", post(set_color))
+        .route("/power", post(set_power))
+        .route("/state", get(state))
+        .route("/metrics", get(metrics))
+        .route("/ws", get(ws))
+        .with_state(AppState { group, states });
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    println!("HTTP server listening on {}", addr);
+    axum::serve(listener, app).await?;
+
+    Ok(())
+}
+
+async fn set_color(State(group): State<SharedGroup>, Json(request): Json<ColorRequest>) -> Result<StatusCode, ApiError> {
+    group
+        .lock()
+        .await
+        .set_color(request.h, request.s, request.v, request.immediate)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn set_power(State(group): State<SharedGroup>, Json(request): Json<PowerRequest>) -> Result<StatusCode, ApiError> {
+    group.lock().await.set_power(request.on).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn state(State(group): State<SharedGroup>) -> Json<Value> {
+    // the last known states, so a slow bulb doesn't hold up the other routes
+    let bulbs: Vec<Value> = group
+        .lock()
+        .await
+        .iter_mut()
+        .map(|controller| bulb_json(controller.config().label(), &controller.cached_state()))
+        .collect();
+
+    Json(json!({ "bulbs": bulbs }))
+}
+
+fn bulb_json(name: &str, state: &BulbState) -> Value {
+    json!({
+        "name": name,
+        "power": state.power,
+        "hue": state.hue,
+        "saturation": state.saturation,
+        "brightness": state.brightness,
+        "work_mode": state.work_mode.map(|mode| mode.as_str()),
+    })
+}
+
+/// Publish the bulbs' cached states whenever they change
+///
+/// Polls the controllers' last known state instead of querying the bulbs, so
+/// it picks up commands from every input and changes the bulbs report on
+/// their own without adding traffic.
+async fn watch_states(group: SharedGroup, states: watch::Sender<Value>) {
+    let mut ticker = tokio::time::interval(STATE_POLL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        ticker.tick().await;
+
+        let bulbs: Vec<Value> = group
+            .lock()
+            .await
+            .iter_mut()
+            .map(|controller| {
+                let state = controller.cached_state();
+                bulb_json(controller.config().label(), &state)
+            })
+            .collect();
+        let latest = json!({ "bulbs": bulbs });
+
+        states.send_if_modified(|current| {
+            if *current == latest {
+                return false;
+            }
+            *current = latest;
+            true
+        });
+    }
+}
+
+async fn ws(upgrade: WebSocketUpgrade, State(states): State<watch::Receiver<Value>>) -> Response {
+    upgrade.on_upgrade(|socket| push_states(socket, states))
+}
+
+/// Send the current state, then every change until the client goes away
+async fn push_states(mut socket: WebSocket, mut states: watch::Receiver<Value>) {
+    loop {
+        let text = states.borrow_and_update().to_string();
+        if socket.send(Message::Text(text)).await.is_err() {
+            return;
+        }
+
+        loop {
+            tokio::select! {
+                changed = states.changed() => {
+                    if changed.is_err() {
+                        socket.send(Message::Close(None)).await.ok();
+                        return;
+                    }
+                    break;
+                }
+                // clients only listen, anything but a close is ignored
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => {}
+                },
+            }
+        }
+    }
+}
+
+async fn metrics(State(group): State<SharedGroup>) -> impl IntoResponse {
+    let group = group.lock().await;
+    let text = prometheus_text(
+        group
+            .iter()
+            .map(|(_, controller)| (controller.config().device_id.as_str(), controller.metrics())),
+    );
+
+    ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], text)
+}
+
+/// A `BulbError` as a response, bad input is the client's fault and anything
+/// else is the bulb's
+struct ApiError(BulbError);
+
+impl From<BulbError> for ApiError {
+    fn from(e: BulbError) -> Self {
+        ApiError(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (status_for(&self.0), Json(json!({ "error": self.0.to_string() }))).into_response()
+    }
+}
+
+fn status_for(e: &BulbError) -> StatusCode {
+    match e {
+        BulbError::OutOfRange { .. } | BulbError::ParseColor(_) => StatusCode::BAD_REQUEST,
+        BulbError::NoSuchBulb(_) => StatusCode::NOT_FOUND,
+        BulbError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+        // bad input fails the same way for every bulb, so the first failure is representative
+        BulbError::Group(failures) => failures.first().map_or(StatusCode::BAD_GATEWAY, |(_, e)| status_for(e)),
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
@@ -1,75 +1,555 @@
 use anyhow::{anyhow, Result};
-use bulb_core::{BulbConfig, BulbController, midi_to_hue};
-use midir::{Ignore, MidiInput, MidiInputConnection};
-use tokio::sync::mpsc;
+use bulb_core::{
+    midi_to_brightness, midi_to_hue, midi_to_saturation, note_to_hue, parse_hex_color, pitch_bend_to_hue, rgb_to_hsv,
+    BulbConfig, BulbController, BulbGroup,
+};
+use clap::Parser;
+use config::{AppConfig, CcRoute, ColorParam, ColorPreset};
+use midi::{MidiChannel, MidiParser};
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiInputPort};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+
+mod artnet;
+mod config;
+mod http;
+mod midi;
+mod mqtt;
+mod osc;
+
+/// Control a Tuya bulb from a MIDI controller
+///
+/// The bulb is taken from the flags if given, otherwise from --config or a
+/// bulb.toml in the working directory, otherwise from the BULB_DEVICE_ID,
+/// BULB_LOCAL_KEY, BULB_IP and BULB_VERSION environment variables. Config
+/// files can also list several `[[bulbs]]` along with a `[[mapping]]` of CCs
+/// to bulbs, and an [mqtt] broker to show up in Home Assistant.
+#[derive(Parser)]
+struct Args {
+    #[arg(long)]
+    device_id: Option<String>,
+    #[arg(long)]
+    local_key: Option<String>,
+    /// IP address or hostname of the bulb
+    #[arg(long)]
+    ip: Option<String>,
+    /// Tuya protocol version, 3.3 or 3.4
+    #[arg(long)]
+    version: Option<String>,
+    /// MIDI input port to listen on, by index or part of its name. Can be left
+    /// out if there is only one port
+    #[arg(long)]
+    midi_port: Option<String>,
+    /// Print the available MIDI input ports and exit
+    #[arg(long)]
+    list_ports: bool,
+    /// CC that controls hue, the mod wheel by default. The CC flags drive every
+    /// bulb and are ignored if the config file has a mapping
+    #[arg(long, default_value_t = 1)]
+    hue_cc: u8,
+    #[arg(long, default_value_t = 2)]
+    saturation_cc: u8,
+    #[arg(long, default_value_t = 3)]
+    brightness_cc: u8,
+    /// MIDI channel to listen on, 1-16 or omni. Defaults to the config file's
+    /// midi_channel, or omni
+    #[arg(long)]
+    midi_channel: Option<MidiChannel>,
+    /// Hex color like "#FF8800" to leave the bulb at on exit, instead of turning it off
+    #[arg(long)]
+    idle_color: Option<String>,
+    /// Also listen for OSC messages on this address, e.g. 0.0.0.0:9000. MIDI is
+    /// optional then
+    #[arg(long)]
+    osc: Option<SocketAddr>,
+    /// Also serve a REST API on this address, e.g. 127.0.0.1:8080. MIDI is
+    /// optional then
+    #[arg(long)]
+    http: Option<SocketAddr>,
+    /// Also act as a DMX fixture over Art-Net on this address, e.g. 0.0.0.0:6454.
+    /// MIDI is optional then
+    #[arg(long)]
+    artnet: Option<SocketAddr>,
+    /// Art-Net universe (port address) the fixture is patched in
+    #[arg(long, default_value_t = 0)]
+    artnet_universe: u16,
+    /// First DMX channel of the fixture, red, green and blue follow from there
+    #[arg(long, default_value_t = 1)]
+    artnet_address: u16,
+    /// Use a fourth channel after blue as a dimmer
+    #[arg(long)]
+    artnet_dimmer: bool,
+    /// TOML file with a [bulb] table, or [[bulbs]] and [[mapping]] tables
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+/// Reconnect attempts after a command fails, a few minutes with the backoff below,
+/// long enough to ride out a router reboot
+const RECONNECT_ATTEMPTS: u32 = 10;
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy)]
+struct Color {
+    hue: u16,
+    saturation: u16,
+    brightness: u16,
+}
+
+/// What the MIDI listener asks the bulbs to do
+#[derive(Debug, Clone, Copy)]
+enum Command {
+    /// Set the color of the bulb at this index in the group
+    Color(usize, Color),
+    /// Turn every bulb on or off
+    Power(bool),
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config = BulbConfig::new(
-        "eb052a1de220394996xwke",
-        "!BY}~:dab1nuT;'n",
-        "192.168.0.124",
-        "3.3",
-    );
-
-    let mut controller = BulbController::new(config)?;
-    println!("Connecting to device...");
-    controller.connect().await?;
+    let args = Args::parse();
+    if args.list_ports {
+        return list_ports();
+    }
+
+    let config = load_config(&args)?;
+    // checked up front so a typo doesn't only show up on exit
+    let idle_color = args.idle_color.as_deref().map(parse_hex_color).transpose()?;
+
+    let bulb_count = config.bulbs.len();
+    let routes = if config.routes.is_empty() {
+        let all: Vec<usize> = (0..bulb_count).collect();
+        vec![
+            CcRoute { cc: args.hue_cc, param: ColorParam::Hue, bulbs: all.clone() },
+            CcRoute { cc: args.saturation_cc, param: ColorParam::Saturation, bulbs: all.clone() },
+            CcRoute { cc: args.brightness_cc, param: ColorParam::Brightness, bulbs: all },
+        ]
+    } else {
+        config.routes
+    };
+
+    let controllers = config.bulbs.into_iter().map(BulbController::new).collect::<bulb_core::Result<_>>()?;
+    let mut group = BulbGroup::new(controllers);
+    println!("Connecting to {} bulb(s)...", bulb_count);
+    group.connect_all().await?;
     println!("Connected!");
 
-    controller.set_color(120, 1000, 1000).await?;
-    println!("Bulb initialized to green");
+    // snap straight to green on startup instead of fading from whatever the bulb was showing,
+    // so it's obvious the app got through
+    group.set_color(120, 1000, 1000, true).await?;
+    group.set_power(true).await?;
+    println!("Bulbs initialized to green");
+
+    // shared with the HTTP server, which needs answers the command channel can't give
+    let group = Arc::new(Mutex::new(group));
+    if let Some(addr) = args.http {
+        let group = group.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(addr, group).await {
+                eprintln!("HTTP server stopped: {}", e);
+            }
+        });
+    }
+    let mqtt_enabled = config.mqtt.is_some();
+    if let Some(mqtt) = config.mqtt {
+        let group = group.clone();
+        tokio::spawn(async move {
+            if let Err(e) = mqtt::run(mqtt, group).await {
+                eprintln!("MQTT bridge stopped: {}", e);
+            }
+        });
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<Command>();
+
+    if let Some(addr) = args.osc {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = osc::serve(addr, tx, bulb_count).await {
+                eprintln!("OSC server stopped: {}", e);
+            }
+        });
+    }
+
+    if let Some(addr) = args.artnet {
+        let patch = artnet::Patch {
+            universe: args.artnet_universe,
+            address: args.artnet_address,
+            dimmer: args.artnet_dimmer,
+        };
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = artnet::serve(addr, patch, tx, bulb_count).await {
+                eprintln!("Art-Net listener stopped: {}", e);
+            }
+        });
+    }
+
+    let channel = args.midi_channel.or(config.midi_channel).unwrap_or(MidiChannel::Omni);
+    let _connection =
+        match start_midi_listener(tx, args.midi_port.as_deref(), channel, routes, config.presets, bulb_count) {
+            Ok(connection) => {
+                println!("MIDI listener started");
+                Some(connection)
+            }
+            Err(e) if args.osc.is_some() || args.http.is_some() || args.artnet.is_some() || mqtt_enabled => {
+                eprintln!("Running without MIDI: {}", e);
+                None
+            }
+            Err(e) => return Err(e),
+        };
+
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+
+    'main: loop {
+        // commands are only picked up here, so Ctrl-C never interrupts one halfway
+        let command = tokio::select! {
+            command = rx.recv() => match command {
+                Some(command) => command,
+                None => break,
+            },
+            _ = &mut shutdown => break,
+        };
+
+        let Err(e) = apply(&group, command).await else {
+            continue;
+        };
 
-    let (tx, mut rx) = mpsc::unbounded_channel::<u16>();
+        // the controller already retried a few times, so the bulb is gone for longer
+        eprintln!("Error sending command to bulb: {}", e);
+        println!("Bulb unreachable, reconnecting...");
+
+        let mut backoff = RECONNECT_BACKOFF_MIN;
+        let mut reconnected = false;
+        for attempt in 1..=RECONNECT_ATTEMPTS {
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = &mut shutdown => break 'main,
+            }
+
+            match group.lock().await.connect_all().await {
+                Ok(()) => {
+                    println!("Reconnected after {} attempt(s)", attempt);
+                    reconnected = true;
+                    break;
+                }
+                Err(e) => {
+                    eprintln!("Reconnect attempt {}/{} failed: {}", attempt, RECONNECT_ATTEMPTS, e);
+                    backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                }
+            }
+        }
 
-    let _connection = start_midi_listener(tx)?;
-    println!("MIDI listener started");
+        if !reconnected {
+            eprintln!("Giving up on the bulb for now, the next MIDI event will try again");
+        }
 
-    tokio::spawn(async move {
-        while let Some(hue) = rx.recv().await {
-            if let Err(e) = controller.set_color(hue, 1000, 1000, true).await {
-                eprintln!("Error setting bulb color: {}", e);
+        // whatever was played during the outage is stale, only the end result matters
+        for command in catch_up(&mut rx, command, bulb_count) {
+            if let Err(e) = apply(&group, command).await {
+                eprintln!("Error sending command to bulb: {}", e);
             }
         }
-    });
+    }
 
-    // keep the connection alive
-    loop {
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    println!("Shutting down...");
+    let mut group = group.lock().await;
+    let result = match idle_color {
+        Some((r, g, b)) => {
+            let (h, s, v) = rgb_to_hsv(r, g, b);
+            match group.set_color(h, s, v, true).await {
+                Ok(()) => group.set_power(true).await,
+                Err(e) => Err(e),
+            }
+        }
+        None => group.set_power(false).await,
+    };
+    if let Err(e) = result {
+        eprintln!("Error resetting bulbs: {}", e);
     }
+    group.disconnect_all().await?;
+
+    Ok(())
 }
 
-fn start_midi_listener(tx: mpsc::UnboundedSender<u16>) -> Result<MidiInputConnection<()>> {
-    let mut midi_in = MidiInput::new("bulb-controller")?;
-    midi_in.ignore(Ignore::None);
+async fn apply(group: &Mutex<BulbGroup>, command: Command) -> bulb_core::Result<()> {
+    let mut group = group.lock().await;
+    match command {
+        Command::Color(index, color) => {
+            group.set_color_one(index, color.hue, color.saturation, color.brightness, true).await?;
+            // playing a note or moving a knob should light the bulb up, setting
+            // the color first so it doesn't flash the old one
+            let bulb = group.get_mut(index)?;
+            if bulb.cached_state().power != Some(true) {
+                bulb.set_power(true).await?;
+            }
+            Ok(())
+        }
+        Command::Power(on) => group.set_power(on).await,
+    }
+}
 
+/// Collapse the queued commands, plus the one that failed, into the ones needed
+/// to reach the same end state: the latest color of each bulb, then the latest
+/// power change if it came after them
+fn catch_up(rx: &mut mpsc::UnboundedReceiver<Command>, failed: Command, bulb_count: usize) -> Vec<Command> {
+    let mut colors = vec![None; bulb_count];
+    let mut power = None;
+
+    let mut next = Some(failed);
+    while let Some(command) = next {
+        match command {
+            Command::Color(index, color) => {
+                colors[index] = Some(color);
+                // applying a color turns the bulb on, so an earlier power change is moot
+                power = None;
+            }
+            Command::Power(on) => power = Some(on),
+        }
+        next = rx.try_recv().ok();
+    }
+
+    colors
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, color)| color.map(|color| Command::Color(index, color)))
+        .chain(power.map(Command::Power))
+        .collect()
+}
+
+fn load_config(args: &Args) -> Result<AppConfig> {
+    let flags = [("--device-id", &args.device_id), ("--local-key", &args.local_key), ("--ip", &args.ip)];
+
+    if flags.iter().any(|(_, value)| value.is_some()) {
+        let missing: Vec<&str> = flags.iter().filter(|(_, value)| value.is_none()).map(|(flag, _)| *flag).collect();
+        if !missing.is_empty() {
+            return Err(anyhow!("Missing {} (pass all of --device-id, --local-key and --ip)", missing.join(", ")));
+        }
+
+        let version = args.version.as_deref().unwrap_or("3.3");
+        let bulb = BulbConfig::new(
+            args.device_id.clone().unwrap(),
+            args.local_key.clone().unwrap(),
+            args.ip.clone().unwrap(),
+            version,
+        )?;
+        return Ok(AppConfig {
+            bulbs: vec![bulb],
+            routes: Vec::new(),
+            midi_channel: None,
+            presets: Vec::new(),
+            mqtt: None,
+        });
+    }
+
+    let mut config = if let Some(path) = &args.config {
+        AppConfig::from_file(path)?
+    } else if Path::new("bulb.toml").exists() {
+        AppConfig::from_file("bulb.toml")?
+    } else {
+        let bulb = BulbConfig::from_env().map_err(|e| {
+            anyhow!(
+                "No bulb credentials found ({}). Pass --device-id, --local-key and --ip, \
+                 point --config at a bulb.toml, or set BULB_DEVICE_ID, BULB_LOCAL_KEY and BULB_IP",
+                e
+            )
+        })?;
+        AppConfig {
+            bulbs: vec![bulb],
+            routes: Vec::new(),
+            midi_channel: None,
+            presets: Vec::new(),
+            mqtt: None,
+        }
+    };
+
+    if let Some(version) = &args.version {
+        for bulb in &mut config.bulbs {
+            bulb.version = version.parse()?;
+        }
+    }
+
+    Ok(config)
+}
+
+fn list_ports() -> Result<()> {
+    let midi_in = MidiInput::new("bulb-controller")?;
+    let in_ports = midi_in.ports();
+    if in_ports.is_empty() {
+        println!("No MIDI input ports available.");
+    }
+
+    for (index, port) in in_ports.iter().enumerate() {
+        println!("{}: {}", index, midi_in.port_name(port)?);
+    }
+
+    Ok(())
+}
+
+/// Find a port by index or by a case insensitive part of its name
+fn select_port(midi_in: &MidiInput, selector: Option<&str>) -> Result<MidiInputPort> {
     let in_ports = midi_in.ports();
     if in_ports.is_empty() {
         return Err(anyhow!("No MIDI input ports available."));
     }
 
-    let in_port = in_ports.get(0).unwrap();
-    let port_name = midi_in.port_name(in_port)?;
+    let Some(selector) = selector else {
+        return match in_ports.as_slice() {
+            [port] => Ok(port.clone()),
+            _ => Err(anyhow!(
+                "{} MIDI input ports available, pick one with --midi-port (see --list-ports)",
+                in_ports.len()
+            )),
+        };
+    };
+
+    if let Ok(index) = selector.parse::<usize>() {
+        return in_ports.get(index).cloned().ok_or_else(|| {
+            anyhow!("MIDI port {} doesn't exist, there are {} ports (see --list-ports)", index, in_ports.len())
+        });
+    }
+
+    let needle = selector.to_lowercase();
+    in_ports
+        .into_iter()
+        .find(|port| {
+            midi_in
+                .port_name(port)
+                .is_ok_and(|name| name.to_lowercase().contains(&needle))
+        })
+        .ok_or_else(|| anyhow!("No MIDI port matching \"{}\" (see --list-ports)", selector))
+}
+
+fn start_midi_listener(
+    tx: mpsc::UnboundedSender<Command>,
+    port: Option<&str>,
+    channel: MidiChannel,
+    routes: Vec<CcRoute>,
+    presets: Vec<ColorPreset>,
+    bulb_count: usize,
+) -> Result<MidiInputConnection<()>> {
+    let mut midi_in = MidiInput::new("bulb-controller")?;
+    midi_in.ignore(Ignore::None);
+
+    let in_port = select_port(&midi_in, port)?;
+    let port_name = midi_in.port_name(&in_port)?;
     println!("Connecting to MIDI port: {}", port_name);
 
+    // matches the green the bulbs are initialized to
+    let green = Color {
+        hue: 120,
+        saturation: 1000,
+        brightness: 1000,
+    };
+    let mut colors = vec![green; bulb_count];
+
+    let mut parser = MidiParser::default();
+    // the bulb is on while any key is held
+    let mut active_notes = HashSet::new();
+
     let connection = midi_in.connect(
-        in_port,
+        &in_port,
         "bulb-controller-input",
         move |_, message, _| {
-            match message {
-                [0xB0, 0x01, value] => {
-                    let hue = midi_to_hue(*value);
-                    println!("Mod Wheel: {} -> Hue: {}", value, hue);
+            parser.parse(message, |status, data| {
+                // keeps a keyboard that also plays instruments from driving the bulbs
+                if !channel.matches(status) {
+                    return;
+                }
 
-                    if let Err(e) = tx.send(hue) {
-                        eprintln!("Error sending hue to channel: {}", e);
+                // the bulbs whose color changed, power changes are sent right away
+                let changed: Vec<usize> = match (status & 0xF0, data) {
+                    // All Notes Off, clears notes stuck by a missed Note Off
+                    (0xB0, [123, _]) => {
+                        active_notes.clear();
+                        println!("All Notes Off -> Power: off");
+                        send(&tx, Command::Power(false));
+                        return;
+                    }
+                    (0xB0, [cc, value]) => {
+                        let mut changed = Vec::new();
+                        for route in routes.iter().filter(|route| route.cc == *cc) {
+                            for &index in &route.bulbs {
+                                let color = &mut colors[index];
+                                match route.param {
+                                    ColorParam::Hue => color.hue = midi_to_hue(*value),
+                                    ColorParam::Saturation => color.saturation = midi_to_saturation(*value),
+                                    ColorParam::Brightness => color.brightness = midi_to_brightness(*value),
+                                }
+                                if !changed.contains(&index) {
+                                    changed.push(index);
+                                }
+                            }
+                            println!("CC {}: {} -> {:?} of {} bulb(s)", cc, value, route.param, route.bulbs.len());
+                        }
+                        changed
+                    }
+                    (0xE0, [lsb, msb]) => {
+                        let bend = (*msb as u16) << 7 | *lsb as u16;
+                        let hue = pitch_bend_to_hue(bend);
+                        // many bend values share a hue, only send when it actually changes
+                        if colors.iter().all(|color| color.hue == hue) {
+                            return;
+                        }
+                        println!("Pitch Bend: {} -> Hue: {}", bend, hue);
+                        colors.iter_mut().for_each(|color| color.hue = hue);
+                        (0..bulb_count).collect()
+                    }
+                    (0xC0, [program]) => {
+                        let Some(preset) = presets.get(*program as usize) else {
+                            eprintln!("Program Change {} has no preset, there are {}", program, presets.len());
+                            return;
+                        };
+                        println!("Program Change: {} -> Preset {}", program, preset.name.as_deref().unwrap_or("(unnamed)"));
+                        for color in &mut colors {
+                            color.hue = preset.hue;
+                            color.saturation = preset.saturation;
+                            color.brightness = preset.brightness;
+                        }
+                        (0..bulb_count).collect()
+                    }
+                    // applying the color also turns the bulb on, see `apply`
+                    (0x90, [note, velocity]) if *velocity > 0 => {
+                        active_notes.insert(*note);
+                        let hue = note_to_hue(*note);
+                        let brightness = midi_to_brightness(*velocity);
+                        println!("Note On: {} ({}) -> Hue: {} Brightness: {}", note, velocity, hue, brightness);
+                        for color in &mut colors {
+                            color.hue = hue;
+                            color.brightness = brightness;
+                        }
+                        (0..bulb_count).collect()
                     }
+                    // a Note On with velocity 0 is a Note Off
+                    (0x80 | 0x90, [note, _]) => {
+                        if active_notes.remove(note) && active_notes.is_empty() {
+                            println!("Note Off: {} -> Power: off", note);
+                            send(&tx, Command::Power(false));
+                        }
+                        return;
+                    }
+                    _ => return,
+                };
+
+                for index in changed {
+                    send(&tx, Command::Color(index, colors[index]));
                 }
-                _ => {}
-            }
+            });
         },
         (),
     )?;
 
     Ok(connection)
 }
+
+fn send(tx: &mpsc::UnboundedSender<Command>, command: Command) {
+    if let Err(e) = tx.send(command) {
+        eprintln!("Error sending command to channel: {}", e);
+    }
+}
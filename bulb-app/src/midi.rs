@@ -0,0 +1,89 @@
+/// Splits raw MIDI input into channel messages
+///
+/// Some devices leave out the status byte when it's the same as the previous
+/// message's (running status), and some backends pass several messages in one
+/// buffer. Both are handled here so callers only see complete messages.
+#[derive(Default)]
+pub struct MidiParser {
+    running_status: Option<u8>,
+}
+
+impl MidiParser {
+    /// Call `handle` with the status byte and data bytes of every channel
+    /// message in `bytes`. System messages are skipped.
+    pub fn parse(&mut self, bytes: &[u8], mut handle: impl FnMut(u8, &[u8])) {
+        let mut i = 0;
+        while i < bytes.len() {
+            let byte = bytes[i];
+            let status = match byte {
+                // realtime messages can show up anywhere, even between data bytes
+                0xF8..=0xFF => {
+                    i += 1;
+                    continue;
+                }
+                // sysex and system common messages cancel running status, and
+                // whatever follows them isn't ours to interpret
+                0xF0..=0xF7 => {
+                    self.running_status = None;
+                    return;
+                }
+                0x80..=0xEF => {
+                    self.running_status = Some(byte);
+                    i += 1;
+                    byte
+                }
+                _ => match self.running_status {
+                    Some(status) => status,
+                    None => {
+                        i += 1;
+                        continue;
+                    }
+                },
+            };
+
+            let data_len = match status & 0xF0 {
+                0xC0 | 0xD0 => 1,
+                _ => 2,
+            };
+            if i + data_len > bytes.len() {
+                return;
+            }
+
+            handle(status, &bytes[i..i + data_len]);
+            i += data_len;
+        }
+    }
+}
+
+/// The channel to listen on, parsed from "omni" or 1-16
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiChannel {
+    Omni,
+    /// 0-15, as in the low nibble of the status byte
+    Channel(u8),
+}
+
+impl MidiChannel {
+    /// Whether a channel message with this status byte should be handled
+    pub fn matches(self, status: u8) -> bool {
+        match self {
+            MidiChannel::Omni => true,
+            MidiChannel::Channel(channel) => status & 0x0F == channel,
+        }
+    }
+}
+
+impl std::str::FromStr for MidiChannel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("omni") {
+            return Ok(MidiChannel::Omni);
+        }
+
+        match s.parse::<u8>() {
+            Ok(channel @ 1..=16) => Ok(MidiChannel::Channel(channel - 1)),
+            _ => Err(format!("invalid MIDI channel \"{}\", expected 1-16 or omni", s)),
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use anyhow::Result;
+use bulb_core::{BulbController, BulbGroup};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Wait before polling again after the broker connection fails, rumqttc
+/// reconnects on the next poll
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The `[mqtt]` table of the config file
+#[derive(Debug, Clone, Deserialize)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    /// Where Home Assistant looks for discovery configs
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Command and state topics are `<topic_prefix>/<device id>/set` and `/state`
+    #[serde(default = "default_topic_prefix")]
+    pub topic_prefix: String,
+}
+
+fn default_port() -> u16 {
+    1883
+}
+
+fn default_client_id() -> String {
+    "bulb-app".to_string()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_topic_prefix() -> String {
+    "bulb".to_string()
+}
+
+/// Expose every bulb in the group to Home Assistant as an MQTT JSON light
+///
+/// Each bulb is announced with a discovery config when the broker connection
+/// comes up. Commands like `{"state": "ON", "brightness": 500, "color": {"h": 120, "s": 100}}`
+/// are mapped onto the controller, and the resulting state is published back,
+/// retained, on the state topic.
+pub async fn run(config: MqttConfig, group: Arc<Mutex<BulbGroup>>) -> Result<()> {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let Some(username) = &config.username {
+        options.set_credentials(username, config.password.as_deref().unwrap_or(""));
+    }
+
+    let (client, mut eventloop) = AsyncClient::new(options, 32);
+    let config = Arc::new(config);
+    println!("Connecting to MQTT broker at {}:{}", config.host, config.port);
+
+    loop {
+        // everything that publishes runs in its own task, the event loop has to
+        // keep being polled for the requests to go out
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                println!("Connected to MQTT broker");
+                tokio::spawn(announce(client.clone(), config.clone(), group.clone()));
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let Some(device_id) = publish
+                    .topic
+                    .strip_prefix(&format!("{}/", config.topic_prefix))
+                    .and_then(|rest| rest.strip_suffix("/set"))
+                else {
+                    continue;
+                };
+
+                let device_id = device_id.to_string();
+                let payload = publish.payload.to_vec();
+                let (client, config, group) = (client.clone(), config.clone(), group.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = handle_command(&client, &config, &group, &device_id, &payload).await {
+                        eprintln!("Error handling MQTT command for {}: {}", device_id, e);
+                    }
+                });
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("MQTT connection error, retrying in {:?}: {}", RETRY_DELAY, e);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+/// Publish a discovery config for every bulb and subscribe to its command topic
+async fn announce(client: AsyncClient, config: Arc<MqttConfig>, group: Arc<Mutex<BulbGroup>>) {
+    let mut group = group.lock().await;
+    for controller in group.iter_mut() {
+        let id = controller.config().device_id.clone();
+        let discovery = json!({
+            "name": controller.config().label(),
+            "unique_id": format!("bulb_{}", id),
+            "schema": "json",
+            "command_topic": command_topic(&config, &id),
+            "state_topic": state_topic(&config, &id),
+            "brightness": true,
+            "brightness_scale": 1000,
+            "supported_color_modes": ["hs"],
+        });
+        let topic = format!("{}/light/{}/config", config.discovery_prefix, id);
+
+        let result = async {
+            client.publish(topic, QoS::AtLeastOnce, true, discovery.to_string()).await?;
+            client.subscribe(command_topic(&config, &id), QoS::AtLeastOnce).await?;
+            publish_state(&client, &config, controller).await
+        }
+        .await;
+        if let Err(e) = result {
+            eprintln!("Error announcing {} over MQTT: {}", id, e);
+        }
+    }
+}
+
+async fn handle_command(
+    client: &AsyncClient,
+    config: &MqttConfig,
+    group: &Mutex<BulbGroup>,
+    device_id: &str,
+    payload: &[u8],
+) -> Result<()> {
+    let command: Value = serde_json::from_slice(payload)?;
+
+    let mut group = group.lock().await;
+    let Some(controller) = group.iter_mut().find(|controller| controller.config().device_id == device_id) else {
+        return Ok(());
+    };
+
+    if command["state"] == "OFF" {
+        controller.set_power(false).await?;
+        return publish_state(client, config, controller).await;
+    }
+
+    let color = command["color"].as_object();
+    let brightness = command["brightness"].as_u64();
+    if color.is_none() && brightness.is_none() {
+        controller.set_power(true).await?;
+        return publish_state(client, config, controller).await;
+    }
+
+    // a command can carry just one of them, the rest comes from the bulb
+    let state = controller.get_state().await?;
+    let hue = color
+        .and_then(|color| color["h"].as_f64())
+        .map(|h| (h.round() as u16).min(360))
+        .or(state.hue)
+        .unwrap_or(0);
+    let saturation = color
+        .and_then(|color| color["s"].as_f64())
+        .map(|s| ((s * 10.0).round() as u16).min(1000))
+        .or(state.saturation)
+        .unwrap_or(1000);
+    let brightness = brightness
+        .map(|v| v.min(1000) as u16)
+        .or(state.brightness)
+        .unwrap_or(1000);
+
+    controller.set_color(hue, saturation, brightness, true).await?;
+    // Home Assistant only sends a color to a light it means to turn on
+    controller.set_power(true).await?;
+    publish_state(client, config, controller).await
+}
+
+async fn publish_state(client: &AsyncClient, config: &MqttConfig, controller: &mut BulbController) -> Result<()> {
+    let state = controller.get_state().await?;
+    let id = controller.config().device_id.clone();
+
+    let payload = json!({
+        "state": if state.power.unwrap_or(false) { "ON" } else { "OFF" },
+        "brightness": state.brightness,
+        "color_mode": "hs",
+        // Home Assistant wants saturation as a percentage
+        "color": {
+            "h": state.hue,
+            "s": state.saturation.map(|s| s as f64 / 10.0),
+        },
+    });
+    client
+        .publish(state_topic(config, &id), QoS::AtLeastOnce, true, payload.to_string())
+        .await?;
+
+    Ok(())
+}
+
+fn command_topic(config: &MqttConfig, device_id: &str) -> String {
+    format!("{}/{}/set", config.topic_prefix, device_id)
+}
+
+fn state_topic(config: &MqttConfig, device_id: &str) -> String {
+    format!("{}/{}/state", config.topic_prefix, device_id)
+}
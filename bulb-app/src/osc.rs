@@ -0,0 +1,101 @@
+use crate::{send, Color, Command};
+use anyhow::Result;
+use bulb_core::rgb_to_hsv;
+use rosc::{OscMessage, OscPacket, OscType};
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// Listen for OSC messages and forward them to every bulb
+///
+/// - `/bulb/hsv h s v`: hue, saturation and brightness, each 0-1
+/// - `/bulb/rgb r g b`: red, green and blue, each 0-1
+/// - `/bulb/power on`: 0 or false turns the bulbs off, anything else on
+///
+/// Arguments can be floats, doubles or ints, and out of range values are
+/// clamped. Bundles are unpacked, their timetags are ignored.
+pub async fn serve(addr: SocketAddr, tx: mpsc::UnboundedSender<Command>, bulb_count: usize) -> Result<()> {
+    let socket = UdpSocket::bind(addr).await?;
+    println!("OSC server listening on {}", addr);
+
+    let mut buf = vec![0; rosc::decoder::MTU];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        match rosc::decoder::decode_udp(&buf[..len]) {
+            Ok((_, packet)) => handle_packet(packet, &tx, bulb_count),
+            Err(e) => eprintln!("Invalid OSC packet from {}: {}", from, e),
+        }
+    }
+}
+
+fn handle_packet(packet: OscPacket, tx: &mpsc::UnboundedSender<Command>, bulb_count: usize) {
+    match packet {
+        OscPacket::Message(message) => handle_message(message, tx, bulb_count),
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                handle_packet(packet, tx, bulb_count);
+            }
+        }
+    }
+}
+
+fn handle_message(message: OscMessage, tx: &mpsc::UnboundedSender<Command>, bulb_count: usize) {
+    let color = match (message.addr.as_str(), message.args.as_slice()) {
+        ("/bulb/hsv", [h, s, v]) => match (unit(h), unit(s), unit(v)) {
+            (Some(h), Some(s), Some(v)) => Color {
+                // 1 wraps around to the same red as 0
+                hue: (h * 360.0).round() as u16 % 360,
+                saturation: (s * 1000.0).round() as u16,
+                brightness: (v * 1000.0).round() as u16,
+            },
+            _ => return invalid(&message),
+        },
+        ("/bulb/rgb", [r, g, b]) => match (unit(r), unit(g), unit(b)) {
+            (Some(r), Some(g), Some(b)) => {
+                let to_byte = |value: f32| (value * 255.0).round() as u8;
+                let (hue, saturation, brightness) = rgb_to_hsv(to_byte(r), to_byte(g), to_byte(b));
+                Color {
+                    hue,
+                    saturation,
+                    brightness,
+                }
+            }
+            _ => return invalid(&message),
+        },
+        ("/bulb/power", [on]) => {
+            let on = match on {
+                OscType::Bool(on) => *on,
+                other => match number(other) {
+                    Some(value) => value != 0.0,
+                    None => return invalid(&message),
+                },
+            };
+            send(tx, Command::Power(on));
+            return;
+        }
+        _ => return invalid(&message),
+    };
+
+    for index in 0..bulb_count {
+        send(tx, Command::Color(index, color));
+    }
+}
+
+fn invalid(message: &OscMessage) {
+    eprintln!("Ignoring OSC message {} {:?}", message.addr, message.args);
+}
+
+fn number(arg: &OscType) -> Option<f32> {
+    match arg {
+        OscType::Float(value) => Some(*value),
+        OscType::Double(value) => Some(*value as f32),
+        OscType::Int(value) => Some(*value as f32),
+        OscType::Long(value) => Some(*value as f32),
+        _ => None,
+    }
+}
+
+/// A number clamped to 0-1, NaN counts as 0
+fn unit(arg: &OscType) -> Option<f32> {
+    number(arg).map(|value| if value.is_nan() { 0.0 } else { value.clamp(0.0, 1.0) })
+}
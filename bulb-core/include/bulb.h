@@ -0,0 +1,43 @@
+/* C interface to bulb-core, built with the `ffi` feature. See src/ffi.rs for
+ * how to build it and the threading rules. */
+
+#ifndef BULB_H
+#define BULB_H
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define BULB_OK 0
+#define BULB_ERR_INVALID_ARGUMENT -1
+#define BULB_ERR_CONNECT -2
+#define BULB_ERR_IO -3
+#define BULB_ERR_INVALID_CONFIG -4
+#define BULB_ERR_OUT_OF_RANGE -5
+#define BULB_ERR_TIMEOUT -6
+#define BULB_ERR_NOT_CONNECTED -7
+#define BULB_ERR_OTHER -8
+
+typedef struct Bulb Bulb;
+
+/* Returns NULL on invalid arguments. version may be NULL for "3.3". */
+Bulb *bulb_new(const char *device_id, const char *local_key, const char *ip, const char *version);
+
+int bulb_connect(Bulb *bulb);
+
+/* h 0-360, s and v 0-1000. Doesn't turn the bulb on, see bulb_set_power */
+int bulb_set_color(Bulb *bulb, uint16_t h, uint16_t s, uint16_t v, bool immediate);
+
+int bulb_set_power(Bulb *bulb, bool on);
+
+/* Disconnects and frees the bulb, call exactly once per bulb_new */
+void bulb_free(Bulb *bulb);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
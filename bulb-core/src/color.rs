@@ -0,0 +1,241 @@
+//! Color conversions between the bulb's HSV ranges and common formats
+//!
+//! The bulb uses hue 0-360 and saturation/value 0-1000.
+
+use crate::{BulbError, Result};
+
+/// Color string encoding expected by the bulb's color DP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorFormat {
+    /// Change mode digit, "hhhhssssvvvv" and the white part, as used by most bulbs
+    Hsv12,
+    /// "rrggbb", as used by some cheaper strips
+    Rgb6,
+}
+
+/// Formats HSV as the bulb's 12 hex digit "hhhhssssvvvv" color string
+pub fn hsv_to_hex(h: u16, s: u16, v: u16) -> String {
+    format!("{:04x}{:04x}{:04x}", h, s, v)
+}
+
+/// Formats RGB as a 6 hex digit "rrggbb" color string
+pub fn rgb_to_hex(r: u8, g: u8, b: u8) -> String {
+    format!("{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Decodes a color DP into HSV, accepting the 12 digit "hhhhssssvvvv" form,
+/// the control string written by `set_color` (change mode digit, HSV, white
+/// part) and 6 digit RGB
+pub(crate) fn decode_hsv(hex: &str) -> Option<(u16, u16, u16)> {
+    let hsv = match hex.len() {
+        6 => {
+            let (r, g, b) = parse_hex_color(hex).ok()?;
+            return Some(rgb_to_hsv(r, g, b));
+        }
+        12 => hex,
+        21 => hex.get(1..13)?,
+        _ => return None,
+    };
+    let part = |i: usize| u16::from_str_radix(hsv.get(i..i + 4)?, 16).ok();
+
+    Some((part(0)?, part(4)?, part(8)?))
+}
+
+/// Parses a "#RRGGBB" or "RRGGBB" hex color string (case-insensitive) into RGB
+pub fn parse_hex_color(hex: &str) -> Result<(u8, u8, u8)> {
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+
+    if digits.len() != 6 {
+        return Err(BulbError::ParseColor(format!("{:?}: expected 6 hex digits, got {}", hex, digits.len())));
+    }
+    if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(BulbError::ParseColor(format!("{:?}: contains non-hex characters", hex)));
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).unwrap();
+    Ok((channel(0), channel(2), channel(4)))
+}
+
+/// Converts 8-bit RGB to the bulb's HSV ranges
+///
+/// Returns hue (0-359), saturation (0-1000) and value (0-1000). Grays have a
+/// saturation of 0 and black has a value of 0.
+pub fn rgb_to_hsv(r: u8, g: u8, b: u8) -> (u16, u16, u16) {
+    let r = r as f32 / 255.0;
+    let g = g as f32 / 255.0;
+    let b = b as f32 / 255.0;
+
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    let h = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let s = if max == 0.0 { 0.0 } else { delta / max };
+
+    ((h.round() as u16) % 360, (s * 1000.0).round() as u16, (max * 1000.0).round() as u16)
+}
+
+/// Converts the bulb's HSV ranges to 8-bit RGB
+///
+/// Inverse of `rgb_to_hsv`, exact up to the rounding of either side. Hue 360
+/// is treated as 0 and saturation/value above 1000 as 1000.
+pub fn hsv_to_rgb(h: u16, s: u16, v: u16) -> (u8, u8, u8) {
+    let sector = (h % 360) as f32 / 60.0;
+    let s = s.min(1000) as f32 / 1000.0;
+    let v = v.min(1000) as f32 / 1000.0;
+
+    let c = v * s;
+    let x = c * (1.0 - (sector % 2.0 - 1.0).abs());
+    let m = v - c;
+
+    let (r, g, b) = match sector as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let channel = |value: f32| ((value + m) * 255.0).round() as u8;
+
+    (channel(r), channel(g), channel(b))
+}
+
+/// Maps a color temperature in Kelvin to the white temperature DP (0-1000)
+///
+/// Most Tuya white bulbs span 2700K (0, warmest) to 6500K (1000, coolest).
+/// Values outside that range are clamped to the ends.
+pub fn kelvin_to_temperature(kelvin: u16) -> u16 {
+    let kelvin = kelvin.clamp(2700, 6500);
+    ((kelvin - 2700) as u32 * 1000 / (6500 - 2700)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The primary and secondary colors, as RGB and in the bulb's HSV ranges
+    const PRIMARIES: [((u8, u8, u8), (u16, u16, u16)); 6] = [
+        ((255, 0, 0), (0, 1000, 1000)),
+        ((255, 255, 0), (60, 1000, 1000)),
+        ((0, 255, 0), (120, 1000, 1000)),
+        ((0, 255, 255), (180, 1000, 1000)),
+        ((0, 0, 255), (240, 1000, 1000)),
+        ((255, 0, 255), (300, 1000, 1000)),
+    ];
+
+    #[test]
+    fn rgb_to_hsv_primaries() {
+        for (rgb, hsv) in PRIMARIES {
+            assert_eq!(rgb_to_hsv(rgb.0, rgb.1, rgb.2), hsv, "{:?}", rgb);
+        }
+    }
+
+    #[test]
+    fn hsv_to_rgb_primaries() {
+        for (rgb, hsv) in PRIMARIES {
+            assert_eq!(hsv_to_rgb(hsv.0, hsv.1, hsv.2), rgb, "{:?}", hsv);
+        }
+    }
+
+    #[test]
+    fn rgb_to_hsv_grays() {
+        assert_eq!(rgb_to_hsv(0, 0, 0), (0, 0, 0));
+        assert_eq!(rgb_to_hsv(64, 64, 64), (0, 0, 251));
+        assert_eq!(rgb_to_hsv(128, 128, 128), (0, 0, 502));
+        assert_eq!(rgb_to_hsv(192, 192, 192), (0, 0, 753));
+        assert_eq!(rgb_to_hsv(255, 255, 255), (0, 0, 1000));
+    }
+
+    #[test]
+    fn rgb_to_hsv_hue_wraps() {
+        assert_eq!(rgb_to_hsv(255, 0, 8).0, 358);
+        // rounds up to 360, which is red again
+        assert_eq!(rgb_to_hsv(255, 0, 1).0, 0);
+    }
+
+    #[test]
+    fn hsv_round_trip() {
+        // 8-bit RGB can't hold every HSV value, so allow for its rounding
+        for h in (0..360).step_by(15) {
+            for s in [500, 750, 1000] {
+                for v in [500, 750, 1000] {
+                    let (r, g, b) = hsv_to_rgb(h, s, v);
+                    let (h2, s2, v2) = rgb_to_hsv(r, g, b);
+
+                    let hue_diff = h.abs_diff(h2).min(360 - h.abs_diff(h2));
+                    assert!(hue_diff <= 3, "hue {} came back as {}", h, h2);
+                    assert!(s.abs_diff(s2) <= 10, "saturation {} came back as {}", s, s2);
+                    assert!(v.abs_diff(v2) <= 4, "value {} came back as {}", v, v2);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rgb_round_trip() {
+        for r in (0..=255).step_by(51) {
+            for g in (0..=255).step_by(51) {
+                for b in (0..=255).step_by(51) {
+                    let (h, s, v) = rgb_to_hsv(r, g, b);
+                    let (r2, g2, b2) = hsv_to_rgb(h, s, v);
+
+                    assert!(
+                        r.abs_diff(r2) <= 1 && g.abs_diff(g2) <= 1 && b.abs_diff(b2) <= 1,
+                        "{:?} came back as {:?}",
+                        (r, g, b),
+                        (r2, g2, b2)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parse_hex_color_rejects_malformed() {
+        for hex in ["", "#", "#12345", "#1234567", "#gg0000", "12 456", "#ff00ff00"] {
+            assert!(
+                matches!(parse_hex_color(hex), Err(BulbError::ParseColor(_))),
+                "{:?} was accepted",
+                hex
+            );
+        }
+    }
+
+    #[test]
+    fn decode_hsv_formats() {
+        assert_eq!(decode_hsv("007803e803e8"), Some((120, 1000, 1000)));
+        assert_eq!(decode_hsv("0007803e803e800000000"), Some((120, 1000, 1000)));
+        assert_eq!(decode_hsv("00ff00"), Some((120, 1000, 1000)));
+    }
+
+    #[test]
+    fn decode_hsv_rejects_malformed() {
+        for hex in ["", "0078", "007803e803e", "007803e803e8a", "0078z3e803e8", "0007803e8z3e800000000", "zz0000"] {
+            assert_eq!(decode_hsv(hex), None, "{:?} was accepted", hex);
+        }
+    }
+
+    #[test]
+    fn kelvin_to_temperature_ends() {
+        assert_eq!(kelvin_to_temperature(2700), 0);
+        assert_eq!(kelvin_to_temperature(4600), 500);
+        assert_eq!(kelvin_to_temperature(6500), 1000);
+    }
+
+    #[test]
+    fn kelvin_to_temperature_clamps() {
+        assert_eq!(kelvin_to_temperature(0), 0);
+        assert_eq!(kelvin_to_temperature(2000), 0);
+        assert_eq!(kelvin_to_temperature(9000), 1000);
+        assert_eq!(kelvin_to_temperature(u16::MAX), 1000);
+    }
+}
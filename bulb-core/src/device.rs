@@ -0,0 +1,157 @@
+//! Lights from other vendors behind one interface
+//!
+//! `LightDevice` covers what every light can do, so code that only sets
+//! colors and power can drive a Tuya bulb and a WLED strip alike. The
+//! methods return boxed futures so devices can be mixed as
+//! `Box<dyn LightDevice>`.
+
+use crate::{check_range, hsv_to_rgb, BulbController, BulbError, Result};
+use futures::future::BoxFuture;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// How long to wait for a WLED request, including connecting
+const WLED_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A light that can be switched and colored
+///
+/// Values use the same ranges as `BulbController`: hue 0-360, saturation
+/// and brightness 0-1000.
+pub trait LightDevice: Send {
+    /// A name for logs and errors
+    fn label(&self) -> &str;
+
+    fn connect(&mut self) -> BoxFuture<'_, Result<()>>;
+
+    /// `immediate` skips the device's own transition. Leaves the power
+    /// alone, a light that's off stays off
+    fn set_color(&mut self, h: u16, s: u16, v: u16, immediate: bool) -> BoxFuture<'_, Result<()>>;
+
+    fn set_power(&mut self, on: bool) -> BoxFuture<'_, Result<()>>;
+}
+
+impl LightDevice for BulbController {
+    fn label(&self) -> &str {
+        self.config().label()
+    }
+
+    fn connect(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(BulbController::connect(self))
+    }
+
+    fn set_color(&mut self, h: u16, s: u16, v: u16, immediate: bool) -> BoxFuture<'_, Result<()>> {
+        Box::pin(BulbController::set_color(self, h, s, v, immediate))
+    }
+
+    fn set_power(&mut self, on: bool) -> BoxFuture<'_, Result<()>> {
+        Box::pin(BulbController::set_power(self, on))
+    }
+}
+
+/// A WLED controller, driven through its JSON API (`/json/state`)
+///
+/// The color is applied to the selected segments at full value, with the
+/// brightness going to WLED's master brightness so its own gamma and limits
+/// apply. Each command is a separate HTTP request, WLED doesn't keep
+/// connections.
+#[derive(Debug, Clone)]
+pub struct WledDevice {
+    /// `host` or `host:port`, port 80 if left out
+    host: String,
+}
+
+impl WledDevice {
+    pub fn new(host: impl Into<String>) -> Self {
+        Self { host: host.into() }
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Query the current state as WLED reports it
+    pub async fn get_state(&self) -> Result<Value> {
+        let body = self.request("GET", "/json/state", None).await?;
+        serde_json::from_str(&body).map_err(|e| BulbError::Http(format!("invalid JSON from {}: {}", self.host, e)))
+    }
+
+    /// Merge `state` into WLED's state, see the WLED JSON API for the keys
+    pub async fn set_state(&self, state: Value) -> Result<()> {
+        self.request("POST", "/json/state", Some(state)).await.map(drop)
+    }
+
+    /// A minimal HTTP/1.1 request, returning the body of a 2xx response
+    async fn request(&self, method: &str, path: &str, body: Option<Value>) -> Result<String> {
+        let addr = if self.host.contains(':') { self.host.clone() } else { format!("{}:80", self.host) };
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            self.host,
+            body.len(),
+            body
+        );
+        log::debug!("Sending {} {} {} to WLED {}", method, path, body, self.host);
+
+        let exchange = async {
+            let mut stream = TcpStream::connect(&addr).await?;
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await?;
+            Ok::<_, std::io::Error>(response)
+        };
+        let response = tokio::time::timeout(WLED_TIMEOUT, exchange)
+            .await
+            .map_err(|_| BulbError::Timeout)?
+            .map_err(|e| BulbError::Http(format!("{}: {}", self.host, e)))?;
+
+        let response = String::from_utf8_lossy(&response);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&*response, ""));
+        let status = head.split_whitespace().nth(1).and_then(|status| status.parse::<u16>().ok());
+        match status {
+            Some(200..=299) => Ok(body.to_string()),
+            _ => Err(BulbError::Http(format!(
+                "{} answered {}",
+                self.host,
+                head.lines().next().unwrap_or("nothing")
+            ))),
+        }
+    }
+}
+
+impl LightDevice for WledDevice {
+    fn label(&self) -> &str {
+        &self.host
+    }
+
+    /// Checks that WLED answers, there's no connection to keep open
+    fn connect(&mut self) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move { self.get_state().await.map(drop) })
+    }
+
+    fn set_color(&mut self, h: u16, s: u16, v: u16, immediate: bool) -> BoxFuture<'_, Result<()>> {
+        Box::pin(async move {
+            check_range("Hue", h, 360)?;
+            check_range("Saturation", s, 1000)?;
+            check_range("Brightness", v, 1000)?;
+
+            let (r, g, b) = hsv_to_rgb(h, s, 1000);
+            let mut state = json!({
+                "bri": (v as u32 * 255 + 500) / 1000,
+                "seg": { "col": [[r, g, b]] },
+            });
+            if immediate {
+                // one-off transition time, the configured one still applies to other changes
+                state["tt"] = json!(0);
+            }
+            self.set_state(state).await
+        })
+    }
+
+    fn set_power(&mut self, on: bool) -> BoxFuture<'_, Result<()>> {
+        Box::pin(self.set_state(json!({ "on": on })))
+    }
+}
@@ -0,0 +1,111 @@
+//! Finding Tuya devices on the local network
+//!
+//! Tuya devices broadcast their ID and IP over UDP every few seconds, as plain
+//! JSON on port 6666 (protocol 3.1) or encrypted with a well-known key on port
+//! 6667 (protocol 3.3 and newer).
+
+use crate::{BulbError, Result};
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::Aes128;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const PREFIX: [u8; 4] = [0x00, 0x00, 0x55, 0xaa];
+
+/// Key all devices use to encrypt their broadcasts (the MD5 of this string)
+const BROADCAST_KEY: &[u8] = b"yGAdlopoPVldABfn";
+
+/// A device found by `discover`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveredDevice {
+    pub device_id: String,
+    pub ip: String,
+    pub version: Option<String>,
+    pub product_key: Option<String>,
+}
+
+/// Listen for device broadcasts for `timeout`
+///
+/// Each device is returned once, with the IP from its latest broadcast.
+/// Devices broadcast roughly every 5 seconds, so a shorter timeout may miss
+/// some of them.
+pub async fn discover(timeout: Duration) -> Result<Vec<DiscoveredDevice>> {
+    let plain = UdpSocket::bind(("0.0.0.0", 6666)).await.map_err(BulbError::Discovery)?;
+    let encrypted = UdpSocket::bind(("0.0.0.0", 6667)).await.map_err(BulbError::Discovery)?;
+
+    let mut devices = HashMap::new();
+    let mut plain_buf = [0u8; 1024];
+    let mut encrypted_buf = [0u8; 1024];
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    loop {
+        let device = tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => break,
+            received = plain.recv_from(&mut plain_buf) => {
+                let (len, _) = received.map_err(BulbError::Discovery)?;
+                decode_broadcast(&plain_buf[..len], false)
+            }
+            received = encrypted.recv_from(&mut encrypted_buf) => {
+                let (len, _) = received.map_err(BulbError::Discovery)?;
+                decode_broadcast(&encrypted_buf[..len], true)
+            }
+        };
+
+        match device {
+            Some(device) => {
+                devices.insert(device.device_id.clone(), device);
+            }
+            None => log::debug!("Ignoring unrecognized discovery broadcast"),
+        }
+    }
+
+    Ok(devices.into_values().collect())
+}
+
+/// Decodes a broadcast packet: prefix, sequence number, command, length,
+/// return code, payload, CRC and suffix
+fn decode_broadcast(packet: &[u8], encrypted: bool) -> Option<DiscoveredDevice> {
+    if packet.get(..4)? != PREFIX {
+        return None;
+    }
+
+    let len = u32::from_be_bytes(packet.get(12..16)?.try_into().ok()?) as usize;
+    let payload = packet.get(20..(16 + len).checked_sub(8)?)?;
+    let payload = if encrypted { decrypt(payload)? } else { payload.to_vec() };
+
+    let info: Value = serde_json::from_slice(&payload).ok()?;
+    let field = |name: &str| info.get(name).and_then(Value::as_str).map(str::to_string);
+
+    Some(DiscoveredDevice {
+        device_id: field("gwId")?,
+        ip: field("ip")?,
+        version: field("version"),
+        product_key: field("productKey"),
+    })
+}
+
+/// AES-128-ECB with PKCS#7 padding
+fn decrypt(data: &[u8]) -> Option<Vec<u8>> {
+    if data.is_empty() || data.len() % 16 != 0 {
+        return None;
+    }
+
+    let key = md5::compute(BROADCAST_KEY);
+    let cipher = Aes128::new(GenericArray::from_slice(&key.0));
+
+    let mut data = data.to_vec();
+    for block in data.chunks_exact_mut(16) {
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+    }
+
+    let padding = *data.last()? as usize;
+    if padding == 0 || padding > 16 {
+        return None;
+    }
+    data.truncate(data.len() - padding);
+
+    Some(data)
+}
@@ -0,0 +1,118 @@
+use rust_async_tuyapi::error::ErrorKind;
+
+pub type Result<T, E = BulbError> = std::result::Result<T, E>;
+
+/// Errors returned by `bulb-core`
+#[derive(Debug, thiserror::Error)]
+pub enum BulbError {
+    /// Couldn't connect to the bulb
+    #[error("Failed to connect to bulb: {0}")]
+    Connect(#[source] ErrorKind),
+    /// Writing a command to the bulb failed
+    #[error("Failed to send command to bulb: {0}")]
+    Send(#[source] ErrorKind),
+    /// Any other exchange with the bulb failed, e.g. a query or receiving
+    /// its status
+    #[error("Failed to communicate with bulb: {0}")]
+    Io(#[source] ErrorKind),
+    /// An error from the tuya crate passed on with `?`, where it isn't known
+    /// whether connecting, sending or something else failed
+    #[error("Tuya error: {0}")]
+    Tuya(#[from] ErrorKind),
+    /// The config is invalid, e.g. a malformed IP address
+    #[error("Invalid bulb config: {0}")]
+    InvalidConfig(String),
+    /// The bulb's hostname couldn't be resolved
+    #[error("Failed to resolve bulb address: {0}")]
+    Resolve(String),
+    /// A value is outside the range the bulb accepts
+    #[error("{name} {value} is out of range (0-{max})")]
+    OutOfRange { name: &'static str, value: u32, max: u32 },
+    /// A color string couldn't be parsed
+    #[error("Invalid color: {0}")]
+    ParseColor(String),
+    /// A command was issued before connecting
+    #[error("Not connected to bulb")]
+    NotConnected,
+    /// The bulb didn't answer in time
+    #[error("Timed out waiting for bulb")]
+    Timeout,
+    /// The bulb sent something unexpected or closed the connection
+    #[error("Unexpected response from bulb: {0}")]
+    Protocol(String),
+    /// A command couldn't be put together, e.g. the system clock is before 1970
+    #[error("Failed to encode command: {0}")]
+    Encode(String),
+    /// A request to a WLED device failed or was refused
+    #[error("WLED request failed: {0}")]
+    Http(String),
+    /// Listening for device broadcasts failed
+    #[error("Device discovery failed: {0}")]
+    Discovery(#[source] std::io::Error),
+    /// A bulb index or name doesn't exist in a group
+    #[error("No such bulb: {0}")]
+    NoSuchBulb(String),
+    /// Commands to some bulbs in a group failed, labelled by name or device ID
+    #[error("{} bulb(s) failed{}", .0.len(), group_failures(.0))]
+    Group(Vec<(String, BulbError)>),
+    /// The task behind a `BulbHandle` has stopped
+    #[error("Bulb task has stopped")]
+    Closed,
+}
+
+/// "; label: error" for each failure in a `BulbError::Group`
+fn group_failures(failures: &[(String, BulbError)]) -> String {
+    failures.iter().map(|(label, e)| format!("; {}: {}", label, e)).collect()
+}
+
+impl From<std::net::AddrParseError> for BulbError {
+    fn from(e: std::net::AddrParseError) -> Self {
+        BulbError::InvalidConfig(format!("invalid IP address: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use std::net::IpAddr;
+
+    #[test]
+    fn addr_parse_error_is_invalid_config() {
+        let parse = || -> Result<IpAddr> { Ok("192.168.1.999".parse::<IpAddr>()?) };
+        let e = parse().unwrap_err();
+
+        assert!(matches!(&e, BulbError::InvalidConfig(msg) if msg.starts_with("invalid IP address")), "{:?}", e);
+        assert!(e.source().is_none());
+    }
+
+    #[test]
+    fn display() {
+        let e = BulbError::OutOfRange { name: "Hue", value: 361, max: 360 };
+        assert_eq!(e.to_string(), "Hue 361 is out of range (0-360)");
+
+        let e = BulbError::Group(vec![
+            ("desk".to_string(), BulbError::Timeout),
+            ("bf0123456789abcdefgh".to_string(), BulbError::NotConnected),
+        ]);
+        assert_eq!(
+            e.to_string(),
+            "2 bulb(s) failed; desk: Timed out waiting for bulb; bf0123456789abcdefgh: Not connected to bulb"
+        );
+    }
+
+    #[test]
+    fn source_is_the_underlying_error() {
+        let e = BulbError::Discovery(std::io::Error::new(std::io::ErrorKind::AddrInUse, "port 6667 is taken"));
+        assert_eq!(e.to_string(), "Device discovery failed: port 6667 is taken");
+        assert!(e.source().is_some());
+        assert!(BulbError::Timeout.source().is_none());
+    }
+
+    #[test]
+    fn bulb_error_crosses_threads() {
+        // so it can come back from the VST's bulb thread and `BulbHandle`'s task
+        fn check<T: Send + 'static>() {}
+        check::<BulbError>();
+    }
+}
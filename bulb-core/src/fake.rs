@@ -0,0 +1,527 @@
+//! A stand-in for a real bulb, for testing without hardware
+//!
+//! Enabled with the `fake-device` feature. `FakeTuyaDevice` listens on the
+//! Tuya port and speaks enough of protocol 3.3 for a `BulbController` to
+//! connect, write datapoints and query them back. Protocol 3.4's session
+//! negotiation isn't implemented.
+//!
+//! The tuya crate always connects to `TUYA_PORT`, so each fake needs its own
+//! loopback address to run alongside others: on Linux every address in
+//! 127.0.0.0/8 works without setup. In CI, run the tests with
+//! `cargo test -p bulb-core --features fake-device`; no bulb or network
+//! access is needed.
+
+use crate::{BulbConfig, BulbError, Result, TUYA_PORT};
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use serde_json::{json, Map, Value};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+const PREFIX: [u8; 4] = [0x00, 0x00, 0x55, 0xaa];
+const SUFFIX: [u8; 4] = [0x00, 0x00, 0xaa, 0x55];
+const VERSION_HEADER_LEN: usize = 15;
+
+const CONTROL: u32 = 7;
+const STATUS: u32 = 8;
+const HEART_BEAT: u32 = 9;
+const DP_QUERY: u32 = 10;
+
+/// A fake bulb listening on `<ip>:6668`
+///
+/// ```no_run
+/// # async fn run() -> bulb_core::Result<()> {
+/// use bulb_core::{BulbController, FakeTuyaDevice};
+///
+/// let fake = FakeTuyaDevice::start("bf0123456789abcdefgh", "0123456789abcdef", "127.0.0.2".parse().unwrap()).await?;
+/// let mut controller = BulbController::new(fake.config()?)?;
+/// controller.connect().await?;
+/// controller.set_power(true).await?;
+/// assert_eq!(fake.dps()["20"], true);
+/// # Ok(())
+/// # }
+/// ```
+pub struct FakeTuyaDevice {
+    device_id: String,
+    local_key: String,
+    addr: SocketAddr,
+    shared: Arc<Mutex<Shared>>,
+    /// Shared with every config from `config`, see `fail_next_writes`
+    failures: Arc<AtomicUsize>,
+    accept: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct Shared {
+    dps: Map<String, Value>,
+    received: Vec<Map<String, Value>>,
+    connections: Vec<JoinHandle<()>>,
+}
+
+impl FakeTuyaDevice {
+    /// Start listening, must be called within a tokio runtime
+    pub async fn start(device_id: impl Into<String>, local_key: impl Into<String>, ip: IpAddr) -> Result<Self> {
+        let addr = SocketAddr::new(ip, TUYA_PORT);
+        let listener = TcpListener::bind(addr)
+            .await
+            .map_err(|e| BulbError::InvalidConfig(format!("fake device can't listen on {}: {}", addr, e)))?;
+
+        let (device_id, local_key) = (device_id.into(), local_key.into());
+        let shared = Arc::new(Mutex::new(Shared::default()));
+
+        let accept = {
+            let (device_id, key, shared) = (device_id.clone(), local_key.clone(), shared.clone());
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let connection = tokio::spawn(serve(stream, device_id.clone(), key.clone(), shared.clone()));
+                    shared.lock().unwrap().connections.push(connection);
+                }
+            })
+        };
+
+        Ok(Self {
+            device_id,
+            local_key,
+            addr,
+            shared,
+            failures: Arc::new(AtomicUsize::new(0)),
+            accept,
+        })
+    }
+
+    /// A protocol 3.3 config pointing at this device
+    pub fn config(&self) -> Result<BulbConfig> {
+        let mut config = BulbConfig::new(&self.device_id, &self.local_key, self.addr.ip().to_string(), "3.3")?;
+        config.fake_failures = Some(self.failures.clone());
+        Ok(config)
+    }
+
+    /// Fail the next `count` writes from controllers using `config`, like a
+    /// bulb that stops answering until it's reconnected to
+    ///
+    /// The failed writes never reach the device, so they're left out of
+    /// `received`, and the connection stays up for the reconnect.
+    pub fn fail_next_writes(&self, count: usize) {
+        self.failures.store(count, Ordering::Relaxed);
+    }
+
+    /// The current datapoints, as merged from every write
+    pub fn dps(&self) -> Map<String, Value> {
+        self.shared.lock().unwrap().dps.clone()
+    }
+
+    /// Change datapoints as if from the Tuya app, reported on the next query
+    pub fn set_dps(&self, dps: Map<String, Value>) {
+        self.shared.lock().unwrap().dps.extend(dps);
+    }
+
+    /// Every write received so far, oldest first
+    pub fn received(&self) -> Vec<Map<String, Value>> {
+        self.shared.lock().unwrap().received.clone()
+    }
+
+    /// Close every open connection, like a bulb dropping off the network
+    /// and coming back. New connections are still accepted.
+    ///
+    /// The connections are reset rather than shut down, so once that has
+    /// gone through the controller's next write fails like it would with a
+    /// real bulb that had gone away.
+    pub fn drop_connections(&self) {
+        for connection in self.shared.lock().unwrap().connections.drain(..) {
+            connection.abort();
+        }
+    }
+}
+
+impl Drop for FakeTuyaDevice {
+    fn drop(&mut self) {
+        self.accept.abort();
+        self.drop_connections();
+    }
+}
+
+async fn serve(mut stream: TcpStream, device_id: String, key: String, shared: Arc<Mutex<Shared>>) {
+    // closing with a zero linger sends a reset, see `drop_connections`
+    stream.set_linger(Some(Duration::ZERO)).ok();
+    let cipher = Aes128::new(GenericArray::from_slice(key.as_bytes()));
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    loop {
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+
+        while let Some((seq, command, payload, len)) = parse_frame(&buf) {
+            buf.drain(..len);
+            // writes carry the version header, queries don't
+            let encrypted = if payload.starts_with(b"3.3") {
+                payload.get(VERSION_HEADER_LEN..).unwrap_or_default()
+            } else {
+                &payload[..]
+            };
+            let request = decrypt(&cipher, encrypted).and_then(|json| serde_json::from_slice::<Value>(&json).ok());
+
+            let replies = respond(command, request, &device_id, &shared);
+            for (command, body, header) in replies {
+                let frame = encode_frame(seq, command, body.map(|body| encrypt(&cipher, body.to_string().as_bytes())), header);
+                if stream.write_all(&frame).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// The replies to one request: command, JSON body and whether it gets the version header
+fn respond(command: u32, request: Option<Value>, device_id: &str, shared: &Mutex<Shared>) -> Vec<(u32, Option<Value>, bool)> {
+    let mut shared = shared.lock().unwrap();
+
+    match command {
+        CONTROL => {
+            let Some(dps) = request.as_ref().and_then(|request| request["dps"].as_object()) else {
+                return vec![(CONTROL, None, false)];
+            };
+            shared.received.push(dps.clone());
+            shared.dps.extend(dps.clone());
+
+            // real bulbs ack the write, then report the datapoints that changed
+            vec![
+                (CONTROL, None, false),
+                (STATUS, Some(json!({ "devId": device_id, "dps": dps })), true),
+            ]
+        }
+        DP_QUERY => vec![(DP_QUERY, Some(json!({ "devId": device_id, "dps": shared.dps })), false)],
+        HEART_BEAT => vec![(HEART_BEAT, None, false)],
+        _ => Vec::new(),
+    }
+}
+
+/// Sequence number, command, payload and the length of the whole frame
+fn parse_frame(buf: &[u8]) -> Option<(u32, u32, Vec<u8>, usize)> {
+    let start = buf.windows(4).position(|window| window == PREFIX)?;
+    let buf = &buf[start..];
+    let read_u32 = |at: usize| buf.get(at..at + 4).map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()));
+
+    let len = 16 + read_u32(12)? as usize;
+    if buf.len() < len || len < 24 {
+        return None;
+    }
+
+    Some((read_u32(4)?, read_u32(8)?, buf[16..len - 8].to_vec(), start + len))
+}
+
+fn encode_frame(seq: u32, command: u32, payload: Option<Vec<u8>>, header: bool) -> Vec<u8> {
+    let mut body = 0u32.to_be_bytes().to_vec(); // return code
+    if let Some(payload) = payload {
+        if header {
+            body.extend_from_slice(b"3.3");
+            body.extend_from_slice(&[0; VERSION_HEADER_LEN - 3]);
+        }
+        body.extend(payload);
+    }
+
+    let mut frame = PREFIX.to_vec();
+    frame.extend_from_slice(&seq.to_be_bytes());
+    frame.extend_from_slice(&command.to_be_bytes());
+    frame.extend_from_slice(&(body.len() as u32 + 8).to_be_bytes());
+    frame.extend(body);
+    frame.extend_from_slice(&crc32(&frame).to_be_bytes());
+    frame.extend_from_slice(&SUFFIX);
+    frame
+}
+
+/// AES-128-ECB with PKCS#7 padding
+fn encrypt(cipher: &Aes128, data: &[u8]) -> Vec<u8> {
+    let padding = 16 - data.len() % 16;
+    let mut data = data.to_vec();
+    data.resize(data.len() + padding, padding as u8);
+
+    for block in data.chunks_exact_mut(16) {
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+    data
+}
+
+fn decrypt(cipher: &Aes128, data: &[u8]) -> Option<Vec<u8>> {
+    if data.is_empty() || data.len() % 16 != 0 {
+        return None;
+    }
+
+    let mut data = data.to_vec();
+    for block in data.chunks_exact_mut(16) {
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+    }
+
+    let padding = *data.last()? as usize;
+    if padding == 0 || padding > 16 {
+        return None;
+    }
+    data.truncate(data.len() - padding);
+
+    Some(data)
+}
+
+/// CRC-32 (IEEE), as used in the frame trailer
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BulbController, BulbStatus};
+
+    const DEVICE_ID: &str = "bf0123456789abcdefgh";
+    const LOCAL_KEY: &str = "0123456789abcdef";
+
+    /// A fake on a loopback address of its own, so the tests can run in parallel
+    async fn fake(last_octet: u8) -> FakeTuyaDevice {
+        FakeTuyaDevice::start(DEVICE_ID, LOCAL_KEY, IpAddr::from([127, 0, 0, last_octet]))
+            .await
+            .unwrap()
+    }
+
+    /// A connected controller that retries without the usual backoff
+    async fn connect(fake: &FakeTuyaDevice) -> BulbController {
+        let mut config = fake.config().unwrap();
+        config.initial_backoff = Duration::from_millis(10);
+        let mut controller = BulbController::new(config).unwrap();
+        controller.connect().await.unwrap();
+        controller
+    }
+
+    /// Give aborted connections and listeners time to close
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn set_color_reaches_the_device() {
+        let fake = fake(10).await;
+        let mut controller = connect(&fake).await;
+
+        controller.set_color(120, 1000, 1000, true).await.unwrap();
+        controller.set_power(true).await.unwrap();
+
+        let received = fake.received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0]["21"], "colour");
+        assert_eq!(received[0]["28"], "0007803e803e800000000");
+        assert!(!received[0].contains_key("20"));
+        assert_eq!(received[1]["20"], true);
+
+        let dps = fake.dps();
+        assert_eq!(dps["20"], true);
+        assert_eq!(dps["28"], "0007803e803e800000000");
+    }
+
+    #[tokio::test]
+    async fn get_state_reads_the_device() {
+        let fake = fake(11).await;
+        let mut controller = connect(&fake).await;
+
+        let mut dps = Map::new();
+        dps.insert("20".to_string(), json!(false));
+        dps.insert("21".to_string(), json!("colour"));
+        dps.insert("28".to_string(), json!("00f003e801f4"));
+        fake.set_dps(dps);
+
+        let state = controller.get_state().await.unwrap();
+        assert_eq!(state.power, Some(false));
+        assert_eq!((state.hue, state.saturation, state.brightness), (Some(240), Some(1000), Some(500)));
+    }
+
+    #[tokio::test]
+    async fn get_status_decodes_the_answer() {
+        let fake = fake(13).await;
+        let mut controller = connect(&fake).await;
+
+        let mut dps = Map::new();
+        dps.insert("20".to_string(), json!(true));
+        dps.insert("21".to_string(), json!("colour"));
+        dps.insert("28".to_string(), json!("00f003e801f4"));
+        // DPs the controller doesn't know about are skipped
+        dps.insert("101".to_string(), json!("music"));
+        dps.insert("102".to_string(), json!({ "nested": [1, 2] }));
+        fake.set_dps(dps);
+
+        let status = controller.get_status().await.unwrap();
+        assert_eq!(status, BulbStatus { power: true, hue: 240, saturation: 1000, brightness: 500 });
+    }
+
+    #[tokio::test]
+    async fn get_status_ignores_what_was_sent_before() {
+        let fake = fake(14).await;
+        let mut controller = connect(&fake).await;
+        controller.set_power(true).await.unwrap();
+
+        // known to the controller, but the bulb doesn't report a color
+        let mut cached = Map::new();
+        cached.insert("28".to_string(), json!("007803e803e8"));
+        controller.apply_dps(&cached);
+        // so the reports of the writes aren't taken for the answer
+        settle().await;
+        assert!(matches!(controller.get_status().await, Err(BulbError::Protocol(_))));
+
+        controller.set_color(120, 500, 250, true).await.unwrap();
+        let mut dps = Map::new();
+        dps.insert("20".to_string(), json!(false));
+        fake.set_dps(dps);
+        settle().await;
+        let status = controller.get_status().await.unwrap();
+        assert_eq!(status, BulbStatus { power: false, hue: 120, saturation: 500, brightness: 250 });
+    }
+
+    #[tokio::test]
+    async fn dropped_connection_reconnects_and_resends() {
+        let fake = fake(12).await;
+        let mut controller = connect(&fake).await;
+        controller.set_color(120, 1000, 1000, true).await.unwrap();
+
+        fake.drop_connections();
+        settle().await;
+        controller.set_power(false).await.unwrap();
+
+        // the retry carries the color too, in case the bulb reset meanwhile
+        let received = fake.received();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1]["20"], false);
+        assert_eq!(received[1]["28"], "0007803e803e800000000");
+        assert_eq!(fake.dps()["20"], false);
+
+        let metrics = controller.metrics();
+        assert_eq!(metrics.commands_sent, 2);
+        assert_eq!(metrics.reconnects, 1);
+        assert_eq!(metrics.command_failures, 0);
+        assert!(controller.is_connected());
+    }
+
+    #[tokio::test]
+    async fn successful_write_does_not_reconnect() {
+        let fake = fake(20).await;
+        let mut controller = connect(&fake).await;
+
+        controller.set_color(120, 1000, 1000, true).await.unwrap();
+        controller.set_power(true).await.unwrap();
+
+        let metrics = controller.metrics();
+        assert_eq!(metrics.commands_sent, 2);
+        assert_eq!(metrics.reconnects, 0);
+        assert_eq!(metrics.command_failures, 0);
+    }
+
+    #[tokio::test]
+    async fn failed_first_write_reconnects_and_lands() {
+        let fake = fake(21).await;
+        let mut controller = connect(&fake).await;
+
+        fake.fail_next_writes(1);
+        controller.set_power(true).await.unwrap();
+
+        // only the retry got through
+        assert_eq!(fake.received().len(), 1);
+        assert_eq!(fake.dps()["20"], true);
+        let metrics = controller.metrics();
+        assert_eq!(metrics.commands_sent, 1);
+        assert_eq!(metrics.reconnects, 1);
+        assert_eq!(metrics.command_failures, 0);
+        assert!(controller.is_connected());
+    }
+
+    #[tokio::test]
+    async fn permanent_failure_returns_the_error() {
+        let fake = fake(22).await;
+        let mut controller = connect(&fake).await;
+        controller.set_power(true).await.unwrap();
+
+        drop(fake);
+        settle().await;
+        assert!(controller.set_power(false).await.is_err());
+
+        let metrics = controller.metrics();
+        assert_eq!(metrics.commands_sent, 1);
+        assert_eq!(metrics.reconnects, 0);
+        assert_eq!(metrics.command_failures, 1);
+        assert!(!controller.is_connected());
+    }
+
+    #[tokio::test]
+    async fn zero_retries_gives_up_straight_away() {
+        let fake = fake(30).await;
+        let mut controller = connect(&fake).await;
+        controller.set_max_retries(0);
+
+        fake.fail_next_writes(1);
+        assert!(controller.set_power(false).await.is_err());
+        assert!(fake.received().is_empty());
+        assert_eq!(controller.metrics().reconnects, 0);
+        assert_eq!(controller.metrics().command_failures, 1);
+    }
+
+    #[tokio::test]
+    async fn command_lands_when_retries_outlast_failures() {
+        let fake = fake(31).await;
+        let mut controller = connect(&fake).await;
+        controller.set_max_retries(3);
+
+        // the first attempt and one retry fail, the second retry lands
+        fake.fail_next_writes(2);
+        controller.set_power(false).await.unwrap();
+
+        assert_eq!(fake.received().len(), 1);
+        assert_eq!(fake.dps()["20"], false);
+        let metrics = controller.metrics();
+        assert_eq!(metrics.reconnects, 2);
+        assert_eq!(metrics.commands_sent, 1);
+        assert_eq!(metrics.command_failures, 0);
+    }
+
+    #[tokio::test]
+    async fn last_retry_can_still_land() {
+        let fake = fake(32).await;
+        let mut controller = connect(&fake).await;
+        controller.set_max_retries(3);
+
+        fake.fail_next_writes(3);
+        controller.set_power(false).await.unwrap();
+
+        assert_eq!(fake.dps()["20"], false);
+        assert_eq!(controller.metrics().reconnects, 3);
+    }
+
+    #[tokio::test]
+    async fn retries_run_out_after_max_retries() {
+        let fake = fake(33).await;
+        let mut controller = connect(&fake).await;
+        controller.set_max_retries(3);
+
+        // one more failure than there are retries
+        fake.fail_next_writes(4);
+        assert!(controller.set_power(false).await.is_err());
+
+        assert!(fake.received().is_empty());
+        let metrics = controller.metrics();
+        assert_eq!(metrics.reconnects, 3);
+        assert_eq!(metrics.commands_sent, 0);
+        assert_eq!(metrics.command_failures, 1);
+
+        // nothing is left to fail, so the next command goes straight through
+        controller.set_power(false).await.unwrap();
+        assert_eq!(controller.metrics().reconnects, 3);
+    }
+}
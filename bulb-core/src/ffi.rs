@@ -0,0 +1,168 @@
+//! A C interface to `BulbController`, enabled with the `ffi` feature
+//!
+//! Build it as a C library with
+//! `cargo rustc -p bulb-core --release --features ffi --crate-type cdylib`
+//! (or `staticlib`) and include `include/bulb.h`.
+//!
+//! Each `Bulb` owns a small tokio runtime with one worker thread, which keeps
+//! the heartbeat and held back writes going between calls. Every function
+//! blocks the calling thread until the command is done, so don't call them
+//! from a thread that has to stay responsive (like Max's scheduler thread)
+//! or from within another tokio runtime.
+//!
+//! A `Bulb` isn't thread safe: use it from one thread at a time. It's created
+//! by `bulb_new` and must be released exactly once with `bulb_free`, after
+//! which the pointer is invalid.
+
+use crate::{BulbConfig, BulbController, BulbError};
+use std::ffi::{c_char, c_int, CStr};
+use tokio::runtime::Runtime;
+
+pub const BULB_OK: c_int = 0;
+/// A null pointer or a string that isn't UTF-8
+pub const BULB_ERR_INVALID_ARGUMENT: c_int = -1;
+pub const BULB_ERR_CONNECT: c_int = -2;
+pub const BULB_ERR_IO: c_int = -3;
+pub const BULB_ERR_INVALID_CONFIG: c_int = -4;
+pub const BULB_ERR_OUT_OF_RANGE: c_int = -5;
+pub const BULB_ERR_TIMEOUT: c_int = -6;
+pub const BULB_ERR_NOT_CONNECTED: c_int = -7;
+/// Anything else, e.g. an unexpected answer from the bulb
+pub const BULB_ERR_OTHER: c_int = -8;
+
+/// Opaque to C, only ever handled through a pointer
+pub struct Bulb {
+    // declared first so the controller, and its tasks, go before the runtime
+    controller: BulbController,
+    runtime: Runtime,
+}
+
+/// Create a bulb from its device ID, local key, IP address or hostname and
+/// protocol version ("3.3" or "3.4", null for the default "3.3")
+///
+/// Doesn't connect yet. Returns null if an argument is invalid or the
+/// runtime can't be started.
+///
+/// # Safety
+///
+/// The strings must be null or valid, nul terminated C strings.
+#[no_mangle]
+pub unsafe extern "C" fn bulb_new(
+    device_id: *const c_char,
+    local_key: *const c_char,
+    ip: *const c_char,
+    version: *const c_char,
+) -> *mut Bulb {
+    let (Some(device_id), Some(local_key), Some(ip)) = (str_arg(device_id), str_arg(local_key), str_arg(ip)) else {
+        return std::ptr::null_mut();
+    };
+    let version = if version.is_null() { Some("3.3") } else { str_arg(version) };
+    let Some(version) = version else {
+        return std::ptr::null_mut();
+    };
+
+    let runtime = match tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            log::warn!("Failed to start a runtime for bulb {}: {}", device_id, e);
+            return std::ptr::null_mut();
+        }
+    };
+
+    match BulbConfig::new(device_id, local_key, ip, version).and_then(BulbController::new) {
+        Ok(controller) => Box::into_raw(Box::new(Bulb { controller, runtime })),
+        Err(e) => {
+            log::warn!("Invalid config for bulb {}: {}", device_id, e);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Connect to the bulb, returns `BULB_OK` or an error code
+///
+/// # Safety
+///
+/// `bulb` must be null or a pointer from `bulb_new` that hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn bulb_connect(bulb: *mut Bulb) -> c_int {
+    let Some(bulb) = bulb.as_mut() else {
+        return BULB_ERR_INVALID_ARGUMENT;
+    };
+    result_code(bulb.runtime.block_on(bulb.controller.connect()))
+}
+
+/// Set the color, see `BulbController::set_color` for the ranges. Returns
+/// `BULB_OK` or an error code
+///
+/// # Safety
+///
+/// `bulb` must be null or a pointer from `bulb_new` that hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn bulb_set_color(bulb: *mut Bulb, h: u16, s: u16, v: u16, immediate: bool) -> c_int {
+    let Some(bulb) = bulb.as_mut() else {
+        return BULB_ERR_INVALID_ARGUMENT;
+    };
+    result_code(bulb.runtime.block_on(bulb.controller.set_color(h, s, v, immediate)))
+}
+
+/// Turn the bulb on or off, `bulb_set_color` leaves the power alone.
+/// Returns `BULB_OK` or an error code
+///
+/// # Safety
+///
+/// `bulb` must be null or a pointer from `bulb_new` that hasn't been freed.
+#[no_mangle]
+pub unsafe extern "C" fn bulb_set_power(bulb: *mut Bulb, on: bool) -> c_int {
+    let Some(bulb) = bulb.as_mut() else {
+        return BULB_ERR_INVALID_ARGUMENT;
+    };
+    result_code(bulb.runtime.block_on(bulb.controller.set_power(on)))
+}
+
+/// Disconnect and release the bulb. Null is ignored
+///
+/// # Safety
+///
+/// `bulb` must be null or a pointer from `bulb_new`, and mustn't be used
+/// again afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn bulb_free(bulb: *mut Bulb) {
+    if bulb.is_null() {
+        return;
+    }
+
+    let mut bulb = Box::from_raw(bulb);
+    let Bulb { controller, runtime } = &mut *bulb;
+    if let Err(e) = runtime.block_on(controller.disconnect()) {
+        log::debug!("Failed to disconnect from bulb {}: {}", controller.config().device_id, e);
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+fn result_code(result: crate::Result<()>) -> c_int {
+    match result {
+        Ok(()) => BULB_OK,
+        Err(e) => {
+            log::debug!("Bulb command failed: {}", e);
+            error_code(&e)
+        }
+    }
+}
+
+fn error_code(e: &BulbError) -> c_int {
+    match e {
+        BulbError::Connect(_) | BulbError::Resolve(_) => BULB_ERR_CONNECT,
+        BulbError::Send(_) | BulbError::Io(_) | BulbError::Tuya(_) => BULB_ERR_IO,
+        BulbError::InvalidConfig(_) => BULB_ERR_INVALID_CONFIG,
+        BulbError::OutOfRange { .. } | BulbError::ParseColor(_) => BULB_ERR_OUT_OF_RANGE,
+        BulbError::Timeout => BULB_ERR_TIMEOUT,
+        BulbError::NotConnected => BULB_ERR_NOT_CONNECTED,
+        _ => BULB_ERR_OTHER,
+    }
+}
@@ -0,0 +1,103 @@
+use crate::{BulbController, BulbError, Result};
+use futures::future::join_all;
+use std::future::Future;
+
+/// A set of bulbs that are commanded together
+///
+/// Every command is sent to all bulbs concurrently. A bulb failing doesn't stop
+/// the others, and all failures are returned together as `BulbError::Group`.
+pub struct BulbGroup {
+    controllers: Vec<BulbController>,
+}
+
+impl BulbGroup {
+    pub fn new(controllers: Vec<BulbController>) -> Self {
+        Self { controllers }
+    }
+
+    /// The bulb at `index`
+    pub fn get_mut(&mut self, index: usize) -> Result<&mut BulbController> {
+        let len = self.controllers.len();
+        self.controllers
+            .get_mut(index)
+            .ok_or_else(|| BulbError::NoSuchBulb(format!("index {} (group has {} bulbs)", index, len)))
+    }
+
+    /// The bulb whose config has the given name
+    pub fn by_name(&mut self, name: &str) -> Option<&mut BulbController> {
+        self.controllers
+            .iter_mut()
+            .find(|controller| controller.config().name.as_deref() == Some(name))
+    }
+
+    /// Iterate over the bulbs along with their names
+    pub fn iter(&self) -> impl Iterator<Item = (Option<&str>, &BulbController)> {
+        self.controllers
+            .iter()
+            .map(|controller| (controller.config().name.as_deref(), controller))
+    }
+
+    /// Iterate mutably over the bulbs, use `config().name` for their names
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut BulbController> {
+        self.controllers.iter_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.controllers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.controllers.is_empty()
+    }
+
+    /// Connect to every bulb in the group
+    pub async fn connect_all(&mut self) -> Result<()> {
+        self.for_each(|controller| controller.connect()).await
+    }
+
+    /// Disconnect from every bulb in the group
+    pub async fn disconnect_all(&mut self) -> Result<()> {
+        self.for_each(|controller| controller.disconnect()).await
+    }
+
+    /// Set the color of every bulb, see `BulbController::set_color`
+    pub async fn set_color(&mut self, h: u16, s: u16, v: u16, immediate: bool) -> Result<()> {
+        self.for_each(|controller| controller.set_color(h, s, v, immediate)).await
+    }
+
+    /// Turn every bulb on or off
+    pub async fn set_power(&mut self, on: bool) -> Result<()> {
+        self.for_each(|controller| controller.set_power(on)).await
+    }
+
+    /// Set the color of just the bulb at `index`
+    pub async fn set_color_one(&mut self, index: usize, h: u16, s: u16, v: u16, immediate: bool) -> Result<()> {
+        self.get_mut(index)?.set_color(h, s, v, immediate).await
+    }
+
+    /// Set the brightness of every bulb, see `BulbController::set_brightness`
+    pub async fn set_brightness(&mut self, v: u16) -> Result<()> {
+        self.for_each(|controller| controller.set_brightness(v)).await
+    }
+
+    async fn for_each<'a, F, Fut>(&'a mut self, command: F) -> Result<()>
+    where
+        F: FnMut(&'a mut BulbController) -> Fut,
+        Fut: Future<Output = Result<()>> + 'a,
+    {
+        let labels: Vec<String> = self.controllers.iter().map(|c| c.config().label().to_string()).collect();
+        let results = join_all(self.controllers.iter_mut().map(command)).await;
+
+        let failures: Vec<(String, BulbError)> = labels
+            .into_iter()
+            .zip(results)
+            .filter_map(|(label, result)| result.err().map(|e| (label, e)))
+            .collect();
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(BulbError::Group(failures))
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{BulbController, BulbError, BulbState, BulbStatus, Result};
+use futures::future::BoxFuture;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+/// How many commands can wait for the owning task before callers wait too
+const QUEUE_SIZE: usize = 32;
+
+type Job = Box<dyn for<'a> FnOnce(&'a mut BulbController) -> BoxFuture<'a, ()> + Send>;
+
+/// A cloneable handle to a `BulbController` owned by a background task
+///
+/// Commands from every clone are queued and run one at a time, so several
+/// callers (an HTTP server and a MIDI listener, say) can share one bulb. Each
+/// call waits for its own command and returns its result. The controller is
+/// disconnected once the last handle is dropped.
+#[derive(Clone)]
+pub struct BulbHandle {
+    tx: mpsc::Sender<Job>,
+}
+
+impl BulbHandle {
+    /// Move `controller` into a new task, must be called within a tokio runtime
+    pub fn spawn(mut controller: BulbController) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Job>(QUEUE_SIZE);
+
+        tokio::spawn(async move {
+            while let Some(job) = rx.recv().await {
+                job(&mut controller).await;
+            }
+
+            if let Err(e) = controller.disconnect().await {
+                log::debug!("Failed to disconnect from bulb {}: {}", controller.config().device_id, e);
+            }
+        });
+
+        Self { tx }
+    }
+
+    /// Run `command` on the controller once the commands queued before it are done
+    ///
+    /// ```no_run
+    /// # async fn run(handle: bulb_core::BulbHandle) -> bulb_core::Result<()> {
+    /// handle.call(|controller| Box::pin(controller.set_countdown(60))).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn call<T, F>(&self, command: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&'a mut BulbController) -> BoxFuture<'a, Result<T>> + Send + 'static,
+    {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let job: Job = Box::new(move |controller| {
+            Box::pin(async move {
+                // the caller may have given up waiting, that's fine
+                reply_tx.send(command(controller).await).ok();
+            })
+        });
+
+        self.tx.send(job).await.map_err(|_| BulbError::Closed)?;
+        reply_rx.await.map_err(|_| BulbError::Closed)?
+    }
+
+    /// See `BulbController::connect`
+    pub async fn connect(&self) -> Result<()> {
+        self.call(|controller| Box::pin(controller.connect())).await
+    }
+
+    /// See `BulbController::set_color`
+    pub async fn set_color(&self, h: u16, s: u16, v: u16, immediate: bool) -> Result<()> {
+        self.call(move |controller| Box::pin(controller.set_color(h, s, v, immediate))).await
+    }
+
+    /// See `BulbController::fade_to`
+    pub async fn fade_to(&self, h: u16, s: u16, v: u16, duration: Duration) -> Result<()> {
+        self.call(move |controller| Box::pin(controller.fade_to(h, s, v, duration))).await
+    }
+
+    /// See `BulbController::set_brightness`
+    pub async fn set_brightness(&self, v: u16) -> Result<()> {
+        self.call(move |controller| Box::pin(controller.set_brightness(v))).await
+    }
+
+    /// See `BulbController::set_white`
+    pub async fn set_white(&self, brightness: u16, temperature: u16) -> Result<()> {
+        self.call(move |controller| Box::pin(controller.set_white(brightness, temperature))).await
+    }
+
+    /// See `BulbController::set_power`
+    pub async fn set_power(&self, on: bool) -> Result<()> {
+        self.call(move |controller| Box::pin(controller.set_power(on))).await
+    }
+
+    /// See `BulbController::toggle`
+    pub async fn toggle(&self) -> Result<()> {
+        self.call(|controller| Box::pin(controller.toggle())).await
+    }
+
+    /// See `BulbController::get_state`
+    pub async fn get_state(&self) -> Result<BulbState> {
+        self.call(|controller| Box::pin(controller.get_state())).await
+    }
+
+    /// See `BulbController::get_status`
+    pub async fn get_status(&self) -> Result<BulbStatus> {
+        self.call(|controller| Box::pin(controller.get_status())).await
+    }
+}
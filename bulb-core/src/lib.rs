@@ -1,55 +1,938 @@
-use anyhow::Result;
+use rust_async_tuyapi::error::ErrorKind;
+use rust_async_tuyapi::mesparse::Message;
 use rust_async_tuyapi::tuyadevice::TuyaDevice;
 use rust_async_tuyapi::{Payload, PayloadStruct};
-use serde_json::json;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
 use std::str::FromStr;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
 
-#[derive(Debug, Clone)]
+mod color;
+mod device;
+mod discovery;
+mod error;
+#[cfg(feature = "fake-device")]
+mod fake;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod group;
+mod handle;
+mod metrics;
+mod scene;
+
+pub use color::{hsv_to_hex, hsv_to_rgb, kelvin_to_temperature, parse_hex_color, rgb_to_hex, rgb_to_hsv, ColorFormat};
+use color::decode_hsv;
+pub use device::{LightDevice, WledDevice};
+pub use discovery::{discover, DiscoveredDevice};
+pub use error::{BulbError, Result};
+#[cfg(feature = "fake-device")]
+pub use fake::FakeTuyaDevice;
+pub use group::BulbGroup;
+pub use handle::BulbHandle;
+pub use metrics::{prometheus_text, BulbMetrics};
+use metrics::Counters;
+pub use scene::Scene;
+pub use tokio_util::sync::CancellationToken;
+
+/// How long to wait for the bulb to answer a query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest countdown most bulbs accept, in seconds
+const MAX_COUNTDOWN: u32 = 86400;
+
+/// The port Tuya devices listen on for LAN connections
+pub const TUYA_PORT: u16 = 6668;
+
+/// Tuya LAN protocol version spoken by the bulb
+///
+/// 3.4 negotiates a session key when connecting, which the tuya crate handles.
+/// The datapoints are the same in both versions, so the color strings sent by
+/// `set_color` don't change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    V33,
+    V34,
+}
+
+impl ProtocolVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProtocolVersion::V33 => "3.3",
+            ProtocolVersion::V34 => "3.4",
+        }
+    }
+}
+
+impl FromStr for ProtocolVersion {
+    type Err = BulbError;
+
+    fn from_str(version: &str) -> Result<Self> {
+        match version {
+            "3.3" => Ok(ProtocolVersion::V33),
+            "3.4" => Ok(ProtocolVersion::V34),
+            _ => Err(BulbError::InvalidConfig(format!(
+                "unsupported protocol version {:?} (expected \"3.3\" or \"3.4\")",
+                version
+            ))),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct BulbConfig {
     pub device_id: String,
+    /// Left out of the `Debug` output, so configs can be logged safely
     pub local_key: String,
+    /// IP address or hostname of the bulb, hostnames are resolved on every
+    /// connect so a changed DHCP lease is picked up by the next reconnect
     pub ip: String,
-    pub version: String,
+    /// TCP port of the bulb, `None` for the standard `TUYA_PORT`
+    ///
+    /// The tuya crate always connects to `TUYA_PORT`, so anything else is
+    /// rejected by `BulbController::new` rather than silently ignored.
+    pub port: Option<u16>,
+    pub version: ProtocolVersion,
+    /// Optional name used to address the bulb within a `BulbGroup`
+    pub name: Option<String>,
+
+    // datapoint IDs, which differ between bulb models and firmwares
+    pub power_dp: String,
+    pub work_mode_dp: String,
+    pub brightness_dp: String,
+    pub white_temp_dp: String,
+    pub scene_dp: String,
+    pub countdown_dp: String,
+    pub color_dp: String,
+    /// How colors are encoded when written to `color_dp`
+    pub color_format: ColorFormat,
+    /// Gamma curve for the brightness of colors, 1.0 leaves it linear
+    ///
+    /// Many bulbs get bright in the bottom fifth of the range, 2.2 spreads
+    /// that over the whole slider. Only what's sent is corrected: `get_state`
+    /// and fades work with the brightness as passed to `set_color`. White
+    /// mode brightness isn't affected.
+    pub brightness_gamma: f32,
+
+    /// How long to wait for the bulb to accept a connection
+    pub connect_timeout: Duration,
+    /// How often to query the bulb while connected so it doesn't drop an
+    /// idle connection. `None` disables the heartbeat.
+    pub heartbeat_interval: Option<Duration>,
+    /// Minimum time between writes to the bulb. Writes arriving faster than
+    /// this are merged and the latest values sent once the interval is up.
+    /// `None` sends every write straight away.
+    pub min_command_interval: Option<Duration>,
+
+    // reconnect behavior when a command fails
+    pub max_retries: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+
+    /// Record writes instead of sending them, see `BulbController::sent_commands`
+    ///
+    /// Nothing is opened: `connect` and `disconnect` always succeed, commands
+    /// are never throttled, and `get_state` answers from what was recorded.
+    pub dry_run: bool,
+
+    /// Writes left to fail, set by `FakeTuyaDevice::config`
+    #[cfg(feature = "fake-device")]
+    pub(crate) fake_failures: Option<Arc<std::sync::atomic::AtomicUsize>>,
+}
+
+impl fmt::Debug for BulbConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BulbConfig")
+            .field("device_id", &self.device_id)
+            .field("local_key", &"****")
+            .field("ip", &self.ip)
+            .field("port", &self.port)
+            .field("version", &self.version)
+            .field("name", &self.name)
+            .field("power_dp", &self.power_dp)
+            .field("work_mode_dp", &self.work_mode_dp)
+            .field("brightness_dp", &self.brightness_dp)
+            .field("white_temp_dp", &self.white_temp_dp)
+            .field("scene_dp", &self.scene_dp)
+            .field("countdown_dp", &self.countdown_dp)
+            .field("color_dp", &self.color_dp)
+            .field("color_format", &self.color_format)
+            .field("brightness_gamma", &self.brightness_gamma)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("heartbeat_interval", &self.heartbeat_interval)
+            .field("min_command_interval", &self.min_command_interval)
+            .field("max_retries", &self.max_retries)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("dry_run", &self.dry_run)
+            .finish()
+    }
 }
 
 impl BulbConfig {
-    pub fn new(device_id: impl Into<String>, local_key: impl Into<String>, ip: impl Into<String>, version: impl Into<String>) -> Self {
-        Self {
-            device_id: device_id.into(),
-            local_key: local_key.into(),
+    /// Create a config with default datapoints and timings
+    ///
+    /// Fails if `version` isn't a supported protocol version, or if the device
+    /// ID or local key can't be right: device IDs are 10-32 letters and digits
+    /// (usually 20 or 22), and local keys are exactly 16 printable characters.
+    pub fn new(device_id: impl Into<String>, local_key: impl Into<String>, ip: impl Into<String>, version: &str) -> Result<Self> {
+        let (device_id, local_key) = (device_id.into(), local_key.into());
+        check_device_id(&device_id)?;
+        check_local_key(&local_key)?;
+
+        Ok(Self {
+            device_id,
+            local_key,
             ip: ip.into(),
-            version: version.into(),
+            port: None,
+            version: version.parse()?,
+            name: None,
+            power_dp: "20".to_string(),
+            work_mode_dp: "21".to_string(),
+            brightness_dp: "22".to_string(),
+            white_temp_dp: "23".to_string(),
+            scene_dp: "25".to_string(),
+            countdown_dp: "26".to_string(),
+            color_dp: "28".to_string(),
+            color_format: ColorFormat::Hsv12,
+            brightness_gamma: 1.0,
+            connect_timeout: Duration::from_secs(5),
+            heartbeat_interval: Some(Duration::from_secs(10)),
+            min_command_interval: None,
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            dry_run: false,
+            #[cfg(feature = "fake-device")]
+            fake_failures: None,
+        })
+    }
+
+    /// Create a config from the `BULB_DEVICE_ID`, `BULB_LOCAL_KEY`, `BULB_IP`
+    /// and optional `BULB_VERSION` (defaults to "3.3") environment variables
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| {
+            std::env::var(name).map_err(|_| BulbError::InvalidConfig(format!("environment variable {} is not set", name)))
+        };
+        let version = std::env::var("BULB_VERSION").unwrap_or_else(|_| "3.3".to_string());
+
+        Self::new(var("BULB_DEVICE_ID")?, var("BULB_LOCAL_KEY")?, var("BULB_IP")?, &version)
+    }
+
+    /// Load a config from a TOML file, see `from_toml_str`
+    pub fn from_toml_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| BulbError::InvalidConfig(format!("failed to read {}: {}", path.display(), e)))?;
+
+        Self::from_toml_str(&contents)
+    }
+
+    /// Parse a config from a `[bulb]` table with `device_id`, `local_key`,
+    /// `ip`, optional `port`, optional `version` (defaults to "3.3"), optional
+    /// `name` and optional `brightness_gamma`
+    pub fn from_toml_str(toml: &str) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct File {
+            bulb: TomlBulb,
         }
+
+        let file: File = toml::from_str(toml).map_err(|e| BulbError::InvalidConfig(e.to_string()))?;
+        file.bulb.into_config()
+    }
+
+    /// Load several configs from a TOML file, see `list_from_toml_str`
+    pub fn list_from_toml_file(path: impl AsRef<Path>) -> Result<Vec<Self>> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| BulbError::InvalidConfig(format!("failed to read {}: {}", path.display(), e)))?;
+
+        Self::list_from_toml_str(&contents)
+    }
+
+    /// Parse configs from a `[[bulbs]]` array of tables, each with the same
+    /// keys as `from_toml_str`. Other tables in the file are ignored.
+    pub fn list_from_toml_str(toml: &str) -> Result<Vec<Self>> {
+        #[derive(Deserialize)]
+        struct File {
+            bulbs: Vec<TomlBulb>,
+        }
+
+        let file: File = toml::from_str(toml).map_err(|e| BulbError::InvalidConfig(e.to_string()))?;
+        file.bulbs.into_iter().map(TomlBulb::into_config).collect()
+    }
+
+    /// The bulb's name if it has one, otherwise its device ID
+    pub fn label(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.device_id)
+    }
+
+    /// Start building a config with named setters instead of `new`'s positional strings
+    ///
+    /// ```no_run
+    /// # fn run() -> bulb_core::Result<()> {
+    /// use bulb_core::BulbConfig;
+    ///
+    /// let config = BulbConfig::builder()
+    ///     .device_id("bf0123456789abcdefgh")
+    ///     .local_key("0123456789abcdef")
+    ///     .ip("192.168.1.50")
+    ///     .name("desk")
+    ///     .build()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> BulbConfigBuilder {
+        BulbConfigBuilder::default()
     }
 }
 
-pub struct BulbController {
-    device: TuyaDevice,
+/// Builder for `BulbConfig`, see `BulbConfig::builder`
+///
+/// `device_id`, `local_key` and `ip` are required, everything else falls back
+/// to the defaults of `BulbConfig::new`.
+#[derive(Clone, Default)]
+pub struct BulbConfigBuilder {
+    device_id: Option<String>,
+    local_key: Option<String>,
+    ip: Option<String>,
+    version: Option<String>,
+    name: Option<String>,
+    port: Option<u16>,
+    connect_timeout: Option<Duration>,
+    heartbeat_interval: Option<Option<Duration>>,
+    min_command_interval: Option<Duration>,
+    brightness_gamma: Option<f32>,
+    dry_run: bool,
+}
+
+impl BulbConfigBuilder {
+    pub fn device_id(mut self, device_id: impl Into<String>) -> Self {
+        self.device_id = Some(device_id.into());
+        self
+    }
+
+    pub fn local_key(mut self, local_key: impl Into<String>) -> Self {
+        self.local_key = Some(local_key.into());
+        self
+    }
+
+    /// IP address or hostname, see `BulbConfig::ip`
+    pub fn ip(mut self, ip: impl Into<String>) -> Self {
+        self.ip = Some(ip.into());
+        self
+    }
+
+    /// Protocol version, "3.3" (the default) or "3.4"
+    pub fn version(mut self, version: impl Into<String>) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// `None` disables the heartbeat
+    pub fn heartbeat_interval(mut self, interval: Option<Duration>) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+
+    pub fn min_command_interval(mut self, interval: Duration) -> Self {
+        self.min_command_interval = Some(interval);
+        self
+    }
+
+    /// See `BulbConfig::brightness_gamma`
+    pub fn brightness_gamma(mut self, gamma: f32) -> Self {
+        self.brightness_gamma = Some(gamma);
+        self
+    }
+
+    /// See `BulbConfig::dry_run`
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Fails if a required field is missing, the IP is neither an address
+    /// nor a hostname, or the version isn't supported
+    pub fn build(self) -> Result<BulbConfig> {
+        let required = |value: Option<String>, name: &str| {
+            value.ok_or_else(|| BulbError::InvalidConfig(format!("{} is required", name)))
+        };
+
+        let device_id = required(self.device_id, "device_id")?;
+        let local_key = required(self.local_key, "local_key")?;
+        let ip = required(self.ip, "ip")?;
+        if IpAddr::from_str(&ip).is_err() && !is_hostname(&ip) {
+            return Err(BulbError::InvalidConfig(format!(
+                "ip {:?} is neither an IP address nor a hostname",
+                ip
+            )));
+        }
+
+        let mut config = BulbConfig::new(device_id, local_key, ip, self.version.as_deref().unwrap_or("3.3"))?;
+        config.name = self.name;
+        config.port = self.port;
+        if let Some(timeout) = self.connect_timeout {
+            config.connect_timeout = timeout;
+        }
+        if let Some(interval) = self.heartbeat_interval {
+            config.heartbeat_interval = interval;
+        }
+        if let Some(interval) = self.min_command_interval {
+            config.min_command_interval = Some(interval);
+        }
+        if let Some(gamma) = self.brightness_gamma {
+            config.brightness_gamma = gamma;
+        }
+        config.dry_run = self.dry_run;
+
+        Ok(config)
+    }
+}
+
+/// A bulb as written in a config file
+#[derive(Deserialize)]
+struct TomlBulb {
     device_id: String,
+    local_key: String,
+    ip: String,
+    port: Option<u16>,
+    version: Option<String>,
+    name: Option<String>,
+    brightness_gamma: Option<f32>,
+}
+
+impl TomlBulb {
+    fn into_config(self) -> Result<BulbConfig> {
+        let mut config = BulbConfig::new(self.device_id, self.local_key, self.ip, self.version.as_deref().unwrap_or("3.3"))?;
+        config.port = self.port;
+        config.name = self.name;
+        if let Some(gamma) = self.brightness_gamma {
+            config.brightness_gamma = gamma;
+        }
+
+        Ok(config)
+    }
+}
+
+/// The bulb's work mode (`BulbConfig::work_mode_dp`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkMode {
+    Colour,
+    White,
+    Scene,
+}
+
+impl WorkMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WorkMode::Colour => "colour",
+            WorkMode::White => "white",
+            WorkMode::Scene => "scene",
+        }
+    }
+
+    pub fn parse(mode: &str) -> Option<Self> {
+        match mode {
+            "colour" => Some(WorkMode::Colour),
+            "white" => Some(WorkMode::White),
+            "scene" => Some(WorkMode::Scene),
+            _ => None,
+        }
+    }
+}
+
+/// Last known state of the bulb
+///
+/// A field is `None` if the bulb hasn't reported it and the controller hasn't
+/// sent it yet. Brightness is the white brightness in white mode and the HSV
+/// value otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BulbState {
+    pub power: Option<bool>,
+    pub hue: Option<u16>,
+    pub saturation: Option<u16>,
+    pub brightness: Option<u16>,
+    pub work_mode: Option<WorkMode>,
+}
+
+/// The bulb's power and color, see `BulbController::get_status`
+///
+/// Unlike `BulbState` every field is known. The color is the one in the color
+/// DP, also while the bulb is in white mode, with brightness as passed to
+/// `set_color`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BulbStatus {
+    pub power: bool,
+    pub hue: u16,
+    pub saturation: u16,
+    pub brightness: u16,
+}
+
+/// How `BulbController::strobe` flashes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StrobeTiming {
+    /// How long each flash is on
+    pub on_ms: u64,
+    /// How long the bulb is off between flashes
+    pub off_ms: u64,
+    /// Number of flashes
+    pub cycles: u32,
+}
+
+pub struct BulbController {
+    device: Arc<Mutex<TuyaDevice>>,
+    heartbeat: Option<JoinHandle<()>>,
+    config: BulbConfig,
+    rx: Option<Receiver<Result<Vec<Message>, ErrorKind>>>,
+    connected: bool,
+    last_color: Option<(u16, u16, u16)>,
+    white_brightness: Option<u16>,
+    white_temperature: Option<u16>,
+    power: Option<bool>,
+    mode: Option<WorkMode>,
+    fade_step: Duration,
+    throttle: Arc<std::sync::Mutex<Throttle>>,
+    counters: Arc<Counters>,
+    /// Writes recorded in dry run mode
+    sent: Vec<HashMap<String, Value>>,
+    /// Where payload timestamps come from, so tests can wind the clock back
+    clock: fn() -> SystemTime,
+}
+
+/// The last applied state, see `BulbController::restore`
+#[derive(Clone, Copy)]
+struct Snapshot {
+    color: Option<(u16, u16, u16)>,
+    white: Option<(u16, u16)>,
+    power: Option<bool>,
+    mode: Option<WorkMode>,
+}
+
+/// Writes held back by `BulbConfig::min_command_interval`
+#[derive(Default)]
+struct Throttle {
+    last_sent: Option<Instant>,
+    pending: HashMap<String, Value>,
+    flush_scheduled: bool,
+    /// The last write the bulb accepted, cleared when the bulb reports
+    /// something else or the connection is replaced
+    last_payload: Option<HashMap<String, Value>>,
 }
 
 impl BulbController {
     /// Create a new bulb controller
     pub fn new(config: BulbConfig) -> Result<Self> {
-        let device = TuyaDevice::new(
-            &config.version,
-            &config.device_id,
-            Some(&config.local_key),
-            IpAddr::from_str(&config.ip)?,
-        )?;
+        if !(config.brightness_gamma.is_finite() && config.brightness_gamma > 0.0) {
+            return Err(BulbError::InvalidConfig(format!(
+                "brightness_gamma {} should be a positive number",
+                config.brightness_gamma
+            )));
+        }
+        match config.port {
+            None | Some(TUYA_PORT) => {}
+            Some(0) => return Err(BulbError::InvalidConfig("port must be nonzero".to_string())),
+            Some(port) => {
+                return Err(BulbError::InvalidConfig(format!(
+                    "port {} isn't supported, the tuya crate only connects on port {}",
+                    port, TUYA_PORT
+                )))
+            }
+        }
+
+        // a hostname is resolved in connect, which replaces the device before
+        // it's ever used
+        let ip = match IpAddr::from_str(&config.ip) {
+            Ok(ip) => ip,
+            Err(_) if is_hostname(&config.ip) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            Err(e) => return Err(BulbError::InvalidConfig(format!("ip {:?}: {}", config.ip, e))),
+        };
+        let device = tuya_device(&config, ip)?;
 
         Ok(Self {
-            device,
-            device_id: config.device_id,
+            device: Arc::new(Mutex::new(device)),
+            heartbeat: None,
+            config,
+            rx: None,
+            connected: false,
+            last_color: None,
+            white_brightness: None,
+            white_temperature: None,
+            power: None,
+            mode: None,
+            fade_step: Duration::from_millis(30),
+            throttle: Arc::new(std::sync::Mutex::new(Throttle::default())),
+            counters: Arc::new(Counters::default()),
+            sent: Vec::new(),
+            clock: SystemTime::now,
         })
     }
 
     /// Connect to the bulb
+    /// Fails with `BulbError::Timeout` if the bulb doesn't answer within
+    /// `BulbConfig::connect_timeout`
     pub async fn connect(&mut self) -> Result<()> {
-        let _rx = self.device.connect().await?;
+        // never log the local key
+        log::debug!("Connecting to bulb {} at {}", self.config.device_id, self.config.ip);
+        if self.config.dry_run {
+            self.connected = true;
+            return Ok(());
+        }
+
+        let mut device = self.device.lock().await;
+        if IpAddr::from_str(&self.config.ip).is_err() {
+            let ip = resolve(&self.config.ip)
+                .await
+                .inspect_err(|e| log::debug!("Connecting to bulb {} failed: {}", self.config.device_id, e))?;
+            log::debug!("Resolved {} to {}", self.config.ip, ip);
+            *device = tuya_device(&self.config, ip)?;
+        }
+
+        let rx = tokio::time::timeout(self.config.connect_timeout, device.connect())
+            .await
+            .map_err(|_| BulbError::Timeout)
+            .and_then(|result| result.map_err(BulbError::Connect))
+            .inspect_err(|e| log::debug!("Connecting to bulb {} failed: {}", self.config.device_id, e))?;
+        drop(device);
+
+        log::debug!("Connected to bulb {}", self.config.device_id);
+        self.rx = Some(rx);
+        self.throttle.lock().unwrap().last_payload = None;
+        self.connected = true;
+        self.start_heartbeat();
+        Ok(())
+    }
+
+    /// Close the connection to the bulb
+    ///
+    /// Stops the heartbeat and releases the socket. Later commands reconnect
+    /// automatically, the same as after a dropped connection.
+    pub async fn disconnect(&mut self) -> Result<()> {
+        log::debug!("Disconnecting from bulb {}", self.config.device_id);
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
+        self.rx = None;
+        self.connected = false;
+        if self.config.dry_run {
+            return Ok(());
+        }
+
+        self.device.lock().await.disconnect().await.map_err(BulbError::Io)
+    }
+
+    /// Spawn the heartbeat task if it's enabled and not already running
+    /// The task only holds a weak reference to the device and is aborted when
+    /// the controller is dropped.
+    fn start_heartbeat(&mut self) {
+        let Some(interval) = self.config.heartbeat_interval else {
+            return;
+        };
+        if self.heartbeat.as_ref().is_some_and(|task| !task.is_finished()) {
+            return;
+        }
+
+        let device = Arc::downgrade(&self.device);
+        let device_id = self.config.device_id.clone();
+        let clock = self.clock;
+
+        self.heartbeat = Some(tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await; // first tick completes immediately
+
+            loop {
+                ticker.tick().await;
+
+                let Some(device) = device.upgrade() else {
+                    break;
+                };
+                let result = match create_payload(&device_id, &HashMap::new(), clock()) {
+                    Ok(payload) => device.lock().await.get(payload).await.map_err(BulbError::Io),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    // a dead connection is picked up by the next command's retry
+                    log::debug!("Heartbeat to bulb {} failed: {}", device_id, e);
+                }
+            }
+        }));
+    }
+
+    /// The config this controller was created with
+    pub fn config(&self) -> &BulbConfig {
+        &self.config
+    }
+
+    /// Change how many times a failed command is retried, see
+    /// `BulbConfig::max_retries`. Takes effect from the next command.
+    pub fn set_max_retries(&mut self, max_retries: usize) {
+        self.config.max_retries = max_retries;
+    }
+
+    /// Every write recorded so far in dry run mode (`BulbConfig::dry_run`),
+    /// oldest first. Always empty otherwise.
+    ///
+    /// Writes skipped because they repeat the last one aren't recorded, the
+    /// same as they wouldn't be sent.
+    pub fn sent_commands(&self) -> &[HashMap<String, Value>] {
+        &self.sent
+    }
+
+    /// How many commands this controller has sent, failed and reconnected for
+    pub fn metrics(&self) -> BulbMetrics {
+        self.counters.snapshot()
+    }
+
+    /// Whether the last connect or command succeeded
+    ///
+    /// This is cleared as soon as a command fails and set again once a
+    /// reconnect succeeds, so it can lag behind a connection that dropped
+    /// while idle.
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Query the bulb for its current state
+    ///
+    /// The reported datapoints are merged into the controller's last known
+    /// state, so any field the bulb doesn't report keeps the last value this
+    /// controller sent or received (see `BulbState`).
+    pub async fn get_state(&mut self) -> Result<BulbState> {
+        if self.config.dry_run {
+            return Ok(self.state());
+        }
+
+        self.query_dps().await?;
+        Ok(self.state())
+    }
+
+    /// Query the bulb for its power and color, for reconciling a caller's
+    /// own idea of the color with the bulb's after a reconnect
+    ///
+    /// Unlike `get_state` only the bulb's answer is used, never a value this
+    /// controller sent earlier. Fails with `BulbError::Protocol` if the answer
+    /// is missing the power or color DP. DPs the controller doesn't know are
+    /// ignored. In dry run mode the answer is every recorded write merged.
+    pub async fn get_status(&mut self) -> Result<BulbStatus> {
+        let dps = if self.config.dry_run {
+            self.sent.iter().flatten().map(|(dp, value)| (dp.clone(), value.clone())).collect()
+        } else {
+            self.query_dps().await?
+        };
+
+        let power = dps
+            .get(&self.config.power_dp)
+            .and_then(Value::as_bool)
+            .ok_or_else(|| BulbError::Protocol("bulb didn't report its power state".to_string()))?;
+        let (hue, saturation, brightness) = dps
+            .get(&self.config.color_dp)
+            .and_then(Value::as_str)
+            .and_then(decode_hsv)
+            .ok_or_else(|| BulbError::Protocol("bulb didn't report its color".to_string()))?;
+        let brightness = gamma(brightness, 1.0 / self.config.brightness_gamma);
+
+        Ok(BulbStatus { power, hue, saturation, brightness })
+    }
+
+    /// Ask the bulb for its datapoints and return the ones it answers with,
+    /// which are merged into the last known state too
+    async fn query_dps(&mut self) -> Result<Map<String, Value>> {
+        self.drain_status();
+        let payload = create_payload(&self.config.device_id, &HashMap::new(), (self.clock)())?;
+        self.device.lock().await.get(payload).await.map_err(BulbError::Io)?;
+
+        tokio::time::timeout(QUERY_TIMEOUT, self.recv_dps())
+            .await
+            .map_err(|_| BulbError::Timeout)?
+    }
+
+    /// The last known state, without querying the bulb
+    ///
+    /// Merges the status updates the bulb has pushed since the last call, so
+    /// changes from the Tuya app show up here too. Cheap enough to poll.
+    pub fn cached_state(&mut self) -> BulbState {
+        self.drain_status();
+        self.state()
+    }
+
+    /// Wait for the next status update from the bulb
+    ///
+    /// The bulb reports changes made from the Tuya app or a physical switch as
+    /// well as answers to our own commands. Updates that arrive while another
+    /// method is running are still merged into the state, but only the next
+    /// one received here wakes this up. Cancel safe, so it can be used in
+    /// `tokio::select!`. Dropping the controller closes the connection and
+    /// ends the updates.
+    pub async fn recv_status(&mut self) -> Result<BulbState> {
+        self.recv_dps().await?;
+        Ok(self.state())
+    }
+
+    /// Wait for the next message with datapoints and merge them, returns
+    /// what it reported. Cancel safe like `recv_status`.
+    async fn recv_dps(&mut self) -> Result<Map<String, Value>> {
+        loop {
+            let rx = self.rx.as_mut().ok_or(BulbError::NotConnected)?;
+            let messages = rx
+                .recv()
+                .await
+                .ok_or_else(|| BulbError::Protocol("connection closed".to_string()))?
+                .map_err(BulbError::Io)?;
+
+            let mut reported = None::<Map<String, Value>>;
+            for dps in messages.iter().filter_map(|message| payload_dps(&message.payload)) {
+                self.apply_dps(&dps);
+                reported.get_or_insert_with(Map::new).extend(dps);
+            }
+            if let Some(reported) = reported {
+                return Ok(reported);
+            }
+        }
+    }
+
+    fn state(&self) -> BulbState {
+        let brightness = match self.mode {
+            Some(WorkMode::White) => self.white_brightness,
+            _ => self.last_color.map(|(_, _, v)| v),
+        };
+
+        BulbState {
+            power: self.power,
+            hue: self.last_color.map(|(h, _, _)| h),
+            saturation: self.last_color.map(|(_, s, _)| s),
+            brightness,
+            work_mode: self.mode,
+        }
+    }
+
+    /// Merge any status messages the bulb has sent since we last looked
+    fn drain_status(&mut self) {
+        let mut pending = Vec::new();
+        if let Some(rx) = self.rx.as_mut() {
+            while let Ok(Ok(messages)) = rx.try_recv() {
+                pending.extend(messages);
+            }
+        }
+        self.apply_messages(&pending);
+    }
+
+    /// Update the last known state from received messages
+    /// Returns true if any of them carried datapoints
+    fn apply_messages(&mut self, messages: &[Message]) -> bool {
+        let mut applied = false;
+
+        for dps in messages.iter().filter_map(|message| payload_dps(&message.payload)) {
+            self.apply_dps(&dps);
+            applied = true;
+        }
+
+        applied
+    }
+
+    fn apply_dps(&mut self, dps: &Map<String, Value>) {
+        // unknown DPs are ignored, and unknown values leave the last known value untouched
+        if let Some(on) = dps.get(&self.config.power_dp).and_then(Value::as_bool) {
+            self.power = Some(on);
+        }
+        if let Some(mode) = dps.get(&self.config.work_mode_dp).and_then(Value::as_str) {
+            self.mode = WorkMode::parse(mode);
+        }
+        if let Some((h, s, v)) = dps.get(&self.config.color_dp).and_then(Value::as_str).and_then(decode_hsv) {
+            self.last_color = Some((h, s, gamma(v, 1.0 / self.config.brightness_gamma)));
+        }
+        if let Some(brightness) = dps.get(&self.config.brightness_dp).and_then(Value::as_u64) {
+            self.white_brightness = Some(brightness as u16);
+        }
+        if let Some(temperature) = dps.get(&self.config.white_temp_dp).and_then(Value::as_u64) {
+            self.white_temperature = Some(temperature as u16);
+        }
+
+        // changed from the Tuya app or a switch, so the last write has to be
+        // sent again to take effect
+        let mut throttle = self.throttle.lock().unwrap();
+        let changed = throttle.last_payload.as_ref().is_some_and(|last| {
+            dps.iter()
+                .any(|(dp, value)| last.get(dp).is_some_and(|sent| sent != value))
+        });
+        if changed {
+            throttle.last_payload = None;
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            color: self.last_color,
+            white: self.white_temperature.zip(self.white_brightness),
+            power: self.power,
+            mode: self.mode,
+        }
+    }
+
+    /// The datapoints for the last state sent or reported, for a bulb that
+    /// may have forgotten it while disconnected. Only the DPs of the current
+    /// work mode are included, so a bulb in white mode never gets the last
+    /// color sent back and vice versa. Scenes aren't tracked, so only power is
+    /// included for them.
+    fn known_dps(&self) -> HashMap<String, Value> {
+        let mut dps = HashMap::new();
+        match (self.mode, self.last_color) {
+            (Some(WorkMode::White), _) => {
+                dps.insert(self.config.work_mode_dp.clone(), json!(WorkMode::White.as_str()));
+                if let Some(brightness) = self.white_brightness {
+                    dps.insert(self.config.brightness_dp.clone(), json!(brightness));
+                }
+                if let Some(temperature) = self.white_temperature {
+                    dps.insert(self.config.white_temp_dp.clone(), json!(temperature));
+                }
+            }
+            (Some(WorkMode::Colour) | None, Some((h, s, v))) => {
+                dps.insert(self.config.work_mode_dp.clone(), json!(WorkMode::Colour.as_str()));
+                dps.insert(self.config.color_dp.clone(), json!(self.encode_color(h, s, v, true)));
+            }
+            _ => {}
+        }
+        if let Some(on) = self.power {
+            dps.insert(self.config.power_dp.clone(), json!(on));
+        }
+        dps
+    }
+
+    /// Put the bulb back into a previously captured state
+    /// Anything that wasn't known when the snapshot was taken is left alone,
+    /// including the color of a bulb that was in white mode.
+    async fn restore(&mut self, snapshot: Snapshot) -> Result<()> {
+        match (snapshot.mode, snapshot.white, snapshot.color) {
+            (Some(WorkMode::White), Some((temperature, brightness)), _) => self.set_white(brightness, temperature).await?,
+            (Some(WorkMode::Colour) | None, _, Some((h, s, v))) => self.set_color(h, s, v, true).await?,
+            _ => {}
+        }
+
+        if let Some(on) = snapshot.power {
+            self.set_power(on).await?;
+        }
+
         Ok(())
     }
 
@@ -59,52 +942,1114 @@ impl BulbController {
     /// s - Saturation (0-1000)
     /// v - Brightness (0-1000)
     /// immediate - If true, set the color immediately without transition
+    ///
+    /// Out of range values are rejected with an error rather than clamped.
+    ///
+    /// The power isn't touched, so a bulb that's off stays off with the new
+    /// color ready for when it's turned on. Use `set_power` to turn it on.
+    ///
+    /// ```no_run
+    /// # async fn run() -> bulb_core::Result<()> {
+    /// use bulb_core::{BulbConfig, BulbController};
+    ///
+    /// let mut controller = BulbController::new(BulbConfig::from_env()?)?;
+    /// controller.connect().await?;
+    /// controller.set_power(true).await?;
+    /// controller.set_color(120, 1000, 1000, true).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
     pub async fn set_color(&mut self, h: u16, s: u16, v: u16, immediate: bool) -> Result<()> {
-        let immediate_num = if immediate { 0 } else { 1 };
+        check_range("Hue", h, 360)?;
+        check_range("Saturation", s, 1000)?;
+        check_range("Brightness", v, 1000)?;
 
+        let color = self.encode_color(h, s, v, immediate);
         let mut dps = HashMap::new();
-        dps.insert("20".to_string(), json!(true)); // make sure it's on
-        dps.insert("28".to_string(), json!(format!("{}{}00000000", immediate_num, hsv_to_hex(h, s, v)))); // real time set color to avoid gradient transition
+        dps.insert(self.config.work_mode_dp.clone(), json!(WorkMode::Colour.as_str())); // leave white mode if we were in it
+        dps.insert(self.config.color_dp.clone(), json!(color));
 
-        self.send_commands(dps).await
+        self.send_commands(dps).await?;
+        self.last_color = Some((h, s, v));
+        self.mode = Some(WorkMode::Colour);
+
+        Ok(())
     }
 
-    fn create_payload(&self, dps: &HashMap<String, serde_json::Value>) -> Payload {
-        let current_time = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32;
-
-        Payload::Struct(PayloadStruct {
-            dev_id: self.device_id.clone(),
-            gw_id: Some(self.device_id.clone()),
-            uid: None,
-            t: Some(current_time.to_string()),
-            dp_id: None,
-            dps: Some(serde_json::to_value(dps).unwrap()),
-        })
+    /// The `color_dp` value for a color, with the brightness gamma applied
+    fn encode_color(&self, h: u16, s: u16, v: u16, immediate: bool) -> String {
+        let v = gamma(v, self.config.brightness_gamma);
+        match self.config.color_format {
+            ColorFormat::Hsv12 => {
+                let immediate_num = if immediate { 0 } else { 1 };
+                // real time set color to avoid gradient transition
+                format!("{}{}00000000", immediate_num, hsv_to_hex(h, s, v))
+            }
+            ColorFormat::Rgb6 => {
+                let (r, g, b) = hsv_to_rgb(h, s, v);
+                rgb_to_hex(r, g, b)
+            }
+        }
+    }
+
+    /// Set the bulb color like `set_color`, returning the color it replaced
+    ///
+    /// The previous color is the last one sent or reported by the bulb. On
+    /// the very first call, before either has happened, the bulb is queried
+    /// for it first. If that query fails, or the bulb doesn't report a color,
+    /// the error is returned and the new color isn't sent.
+    ///
+    /// ```no_run
+    /// # async fn run(controller: &mut bulb_core::BulbController) -> bulb_core::Result<()> {
+    /// let (h, s, v) = controller.swap_color(0, 1000, 1000, true).await?;
+    /// tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    /// controller.set_color(h, s, v, true).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn swap_color(&mut self, h: u16, s: u16, v: u16, immediate: bool) -> Result<(u16, u16, u16)> {
+        let previous = self.known_color().await?;
+        self.set_color(h, s, v, immediate).await?;
+        Ok(previous)
+    }
+
+    /// The last color sent or reported, asking the bulb if there's none yet
+    async fn known_color(&mut self) -> Result<(u16, u16, u16)> {
+        if self.last_color.is_none() {
+            self.get_state().await?;
+        }
+        self.last_color
+            .ok_or_else(|| BulbError::Protocol("bulb didn't report its color".to_string()))
+    }
+
+    /// Fade from the last color sent to the given HSV color over `duration`
+    ///
+    /// The fade is sent as a series of immediate color changes, one per fade
+    /// step (see `set_fade_step`). Hue takes the shortest way around the color
+    /// wheel. If no color has been sent yet the target is set immediately.
+    pub async fn fade_to(&mut self, h: u16, s: u16, v: u16, duration: Duration) -> Result<()> {
+        self.fade_to_cancellable(h, s, v, duration, &CancellationToken::new()).await
+    }
+
+    /// Like `fade_to`, but stops early once `cancel` is cancelled
+    ///
+    /// The bulb is left at the last step sent, which is also what
+    /// `get_state` and the next `fade_to` start from.
+    pub async fn fade_to_cancellable(
+        &mut self,
+        h: u16,
+        s: u16,
+        v: u16,
+        duration: Duration,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        check_range("Hue", h, 360)?;
+        check_range("Saturation", s, 1000)?;
+        check_range("Brightness", v, 1000)?;
+
+        let Some((start_h, start_s, start_v)) = self.last_color else {
+            return self.set_color(h, s, v, true).await;
+        };
+
+        let step_interval = self.effect_step();
+        let steps = (duration.as_millis() / step_interval.as_millis().max(1)).max(1) as u32;
+        let hue_delta = (h as i32 - start_h as i32 + 540) % 360 - 180;
+
+        for step in 1..=steps {
+            let t = step as f32 / steps as f32;
+            let step_h = (start_h as f32 + hue_delta as f32 * t).round().rem_euclid(360.0) as u16;
+            let step_s = lerp(start_s, s, t);
+            let step_v = lerp(start_v, v, t);
+
+            if cancel.is_cancelled() {
+                return Ok(());
+            }
+            self.set_color(step_h, step_s, step_v, true).await?;
+
+            if step < steps && sleep_or_cancel(step_interval, cancel).await {
+                return Ok(());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Flash the bulb between the given color and off, see `StrobeTiming`
+    ///
+    /// The previous color and power state are restored afterwards, also when
+    /// `cancel` is cancelled part way through. This holds the controller for
+    /// the whole effect, so spawn it on its own task if the caller needs to
+    /// keep working meanwhile.
+    pub async fn strobe(&mut self, h: u16, s: u16, v: u16, timing: StrobeTiming, cancel: &CancellationToken) -> Result<()> {
+        let previous = self.snapshot();
+
+        // the color only has to be sent once, the flashes are just power
+        self.set_color(h, s, v, true).await?;
+        for _ in 0..timing.cycles {
+            self.set_power(true).await?;
+            if sleep_or_cancel(Duration::from_millis(timing.on_ms), cancel).await {
+                break;
+            }
+            self.set_power(false).await?;
+            if sleep_or_cancel(Duration::from_millis(timing.off_ms), cancel).await {
+                break;
+            }
+        }
+
+        self.restore(previous).await
+    }
+
+    /// Pulse the brightness in a sine wave between `min_v` and `max_v`
+    ///
+    /// Each period starts and ends at `min_v`. Runs until `cancel` is
+    /// cancelled, leaving the bulb at whatever brightness it had reached.
+    pub async fn pulse(&mut self, h: u16, s: u16, min_v: u16, max_v: u16, period: Duration, cancel: &CancellationToken) -> Result<()> {
+        check_range("Hue", h, 360)?;
+        check_range("Saturation", s, 1000)?;
+        check_range("Brightness", max_v, 1000)?;
+        check_range("Minimum brightness", min_v, max_v)?;
+
+        let start = Instant::now();
+        let mut ticker = tokio::time::interval(self.effect_step());
+
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => return Ok(()),
+                _ = ticker.tick() => {}
+            }
+
+            let phase = start.elapsed().as_secs_f32() / period.as_secs_f32().max(f32::EPSILON);
+            let level = (1.0 - (phase * std::f32::consts::TAU).cos()) / 2.0;
+
+            self.set_color(h, s, lerp(min_v, max_v, level), true).await?;
+        }
+    }
+
+    /// Set the interval between color updates sent by `fade_to` and `pulse` (defaults to 30ms)
+    ///
+    /// Never goes below `BulbConfig::min_command_interval` so effects don't
+    /// outrun the write throttling.
+    pub fn set_fade_step(&mut self, step: Duration) {
+        self.fade_step = step;
+    }
+
+    fn effect_step(&self) -> Duration {
+        match self.config.min_command_interval {
+            Some(min) => self.fade_step.max(min),
+            None => self.fade_step,
+        }
+    }
+
+    /// Set the bulb color using 8-bit RGB values
+    pub async fn set_color_rgb(&mut self, r: u8, g: u8, b: u8, immediate: bool) -> Result<()> {
+        let (h, s, v) = rgb_to_hsv(r, g, b);
+        self.set_color(h, s, v, immediate).await
+    }
+
+    /// Set the bulb color from a hex string like "#FF8800" or "ff8800"
+    pub async fn set_color_hex(&mut self, hex: &str, immediate: bool) -> Result<()> {
+        let (r, g, b) = parse_hex_color(hex)?;
+        self.set_color_rgb(r, g, b, immediate).await
+    }
+
+    /// Set the bulb brightness, keeping the last color sent
+    ///
+    /// v - Brightness (0-1000)
+    ///
+    /// In white mode only the white brightness is changed. Otherwise the last
+    /// hue and saturation are kept, defaulting to 0 (white) if no color has
+    /// been sent yet.
+    pub async fn set_brightness(&mut self, v: u16) -> Result<()> {
+        check_range("Brightness", v, 1000)?;
+
+        if self.mode == Some(WorkMode::White) {
+            let mut dps = HashMap::new();
+            dps.insert(self.config.brightness_dp.clone(), json!(v));
+            self.send_commands(dps).await?;
+            self.white_brightness = Some(v);
+            return Ok(());
+        }
+
+        let (h, s, _) = self.last_color.unwrap_or((0, 0, 0));
+        self.set_color(h, s, v, true).await
+    }
+
+    /// Make the bulb brighter or dimmer by `delta`, returning the new brightness
+    ///
+    /// The result is clamped to 0-1000. Works in white mode as well, like
+    /// `set_brightness`. If the brightness isn't known yet the bulb is asked
+    /// for it first.
+    pub async fn adjust_brightness(&mut self, delta: i16) -> Result<u16> {
+        let current = match self.state().brightness {
+            Some(v) => v,
+            None => self
+                .get_state()
+                .await?
+                .brightness
+                .ok_or_else(|| BulbError::Protocol("bulb didn't report its brightness".to_string()))?,
+        };
+
+        let v = (current as i32 + delta as i32).clamp(0, 1000) as u16;
+        self.set_brightness(v).await?;
+        Ok(v)
+    }
+
+    /// Rotate the hue by `delta_degrees`, wrapping around the color wheel, and
+    /// return the new hue (0-359)
+    ///
+    /// Saturation and brightness are kept. Like `set_color` this leaves white
+    /// mode. If the color isn't known yet the bulb is asked for it first.
+    pub async fn adjust_hue(&mut self, delta_degrees: i16) -> Result<u16> {
+        let (h, s, v) = self.known_color().await?;
+        let h = (h as i32 + delta_degrees as i32).rem_euclid(360) as u16;
+        self.set_color(h, s, v, true).await?;
+        Ok(h)
+    }
+
+    /// Switch the bulb to white mode
+    ///
+    /// brightness - White brightness (0-1000)
+    /// temperature - Color temperature (0-1000), see `kelvin_to_temperature`
+    ///
+    /// Like `set_color` the power isn't touched, use `set_power` to turn the
+    /// bulb on.
+    pub async fn set_white(&mut self, brightness: u16, temperature: u16) -> Result<()> {
+        check_range("Temperature", temperature, 1000)?;
+        check_range("Brightness", brightness, 1000)?;
+
+        let mut dps = HashMap::new();
+        dps.insert(self.config.work_mode_dp.clone(), json!(WorkMode::White.as_str()));
+        dps.insert(self.config.brightness_dp.clone(), json!(brightness));
+        dps.insert(self.config.white_temp_dp.clone(), json!(temperature));
+
+        self.send_commands(dps).await?;
+        self.mode = Some(WorkMode::White);
+        self.white_brightness = Some(brightness);
+        self.white_temperature = Some(temperature);
+
+        Ok(())
+    }
+
+    /// Switch the bulb to one of its scenes, see `Scene` for the encoding
+    ///
+    /// Leaves the power alone, like `set_color`.
+    pub async fn set_scene(&mut self, scene: Scene) -> Result<()> {
+        let mut dps = HashMap::new();
+        dps.insert(self.config.work_mode_dp.clone(), json!(WorkMode::Scene.as_str()));
+        dps.insert(self.config.scene_dp.clone(), json!(scene.encode()));
+
+        self.send_commands(dps).await?;
+        self.mode = Some(WorkMode::Scene);
+
+        Ok(())
+    }
+
+    /// Turn the bulb off by itself after `seconds`, up to a day (86400)
+    ///
+    /// The countdown runs on the bulb, so it still fires if this controller
+    /// goes away.
+    pub async fn set_countdown(&mut self, seconds: u32) -> Result<()> {
+        check_range("Countdown", seconds, MAX_COUNTDOWN)?;
+        // setting the same countdown again restarts it
+        let dps = HashMap::from([(self.config.countdown_dp.clone(), json!(seconds))]);
+        self.force_send_commands(dps).await
+    }
+
+    /// Cancel a countdown started with `set_countdown`
+    pub async fn cancel_countdown(&mut self) -> Result<()> {
+        let dps = HashMap::from([(self.config.countdown_dp.clone(), json!(0))]);
+        self.force_send_commands(dps).await
+    }
+
+    /// Turn the bulb on or off without changing its color
+    pub async fn set_power(&mut self, on: bool) -> Result<()> {
+        let mut dps = HashMap::new();
+        dps.insert(self.config.power_dp.clone(), json!(on));
+
+        self.send_commands(dps).await?;
+        self.power = Some(on);
+
+        Ok(())
+    }
+
+    /// Flip the bulb's power state
+    ///
+    /// Uses the last power state sent or reported by the bulb. Before either
+    /// has happened the bulb is queried for it first. If that query fails, or
+    /// the bulb doesn't report its power, the error is returned and nothing is
+    /// sent.
+    pub async fn toggle(&mut self) -> Result<()> {
+        let on = !self.known_power().await?;
+        self.set_power(on).await
+    }
+
+    /// The last power state sent or reported, asking the bulb if there's none yet
+    async fn known_power(&mut self) -> Result<bool> {
+        if self.power.is_none() {
+            self.get_state().await?;
+        }
+        self.power
+            .ok_or_else(|| BulbError::Protocol("bulb didn't report its power state".to_string()))
+    }
+
+    /// Write a single raw datapoint
+    ///
+    /// Useful for DPs without a dedicated method (countdown, scene, music mode,
+    /// ...) which vary between bulb firmwares.
+    pub async fn set_dp(&mut self, dp: &str, value: serde_json::Value) -> Result<()> {
+        let mut dps = HashMap::new();
+        dps.insert(dp.to_string(), value);
+        self.send_commands(dps).await
     }
 
     /// Send commands to the bulb
-    /// Automatically reconnects and retries once if the command fails
+    /// Automatically reconnects and retries if the command fails, up to
+    /// `BulbConfig::max_retries` times with a doubling backoff between attempts.
+    /// A retry after reconnecting also resends the last known color and power,
+    /// in case the bulb reset meanwhile.
+    ///
+    /// `dps` maps datapoint IDs to values, e.g. `{"20": true}`. This is the raw
+    /// path used by every other method and can be used directly for DPs the
+    /// controller doesn't know about.
+    ///
+    /// With `BulbConfig::min_command_interval` set, a write that comes too soon
+    /// after the last one returns immediately and is sent, merged with any
+    /// later writes, once the interval is up.
+    ///
+    /// A write identical to the last one the bulb accepted is skipped, unless
+    /// the bulb has since reported different values or reconnected. Use
+    /// `force_send_commands` to send it anyway.
     pub async fn send_commands(&mut self, dps: HashMap<String, serde_json::Value>) -> Result<()> {
-        if let Err(_) = self.device.set(self.create_payload(&dps)).await {
-            println!("Reconnecting to bulb...");
+        // the bulb answers every command, so keep the receiver from backing up
+        self.drain_status();
+
+        {
+            let throttle = self.throttle.lock().unwrap();
+            // anything held back is newer than the last write, so it has to be overridden
+            if throttle.pending.is_empty() && throttle.last_payload.as_ref() == Some(&dps) {
+                log::debug!("Skipping {:?} for bulb {}, it was already sent", dps, self.config.device_id);
+                return Ok(());
+            }
+        }
+
+        self.force_send_commands(dps).await
+    }
+
+    /// Like `send_commands`, but never skips a write identical to the last one
+    pub async fn force_send_commands(&mut self, mut dps: HashMap<String, serde_json::Value>) -> Result<()> {
+        self.drain_status();
+
+        // the flush task writes to the device directly, so dry runs skip throttling
+        if let Some(interval) = self.config.min_command_interval.filter(|_| !self.config.dry_run) {
+            let mut throttle = self.throttle.lock().unwrap();
+            let now = Instant::now();
+
+            if let Some(ready) = throttle.last_sent.map(|sent| sent + interval).filter(|ready| *ready > now) {
+                log::debug!(
+                    "Holding back {:?} for bulb {} for {:?}",
+                    dps,
+                    self.config.device_id,
+                    ready - now
+                );
+                throttle.pending.extend(dps);
+                if !throttle.flush_scheduled {
+                    throttle.flush_scheduled = true;
+                    self.schedule_flush(ready);
+                }
+                return Ok(());
+            }
+
+            // anything still held back is older than this write
+            let mut merged = std::mem::take(&mut throttle.pending);
+            merged.extend(dps);
+            dps = merged;
+            throttle.last_sent = Some(now);
+        }
+
+        let mut result = self.try_send(&dps).await;
+        let mut backoff = self.config.initial_backoff;
+
+        for attempt in 1..=self.config.max_retries {
+            let Err(e) = &result else { break };
+            if matches!(e, BulbError::Encode(_)) {
+                // nothing went out, a reconnect wouldn't help
+                break;
+            }
+            self.connected = false;
+
             // connection likely failed or was dropped. reconnect and try again
-            self.connect().await?;
-            println!("Reconnected. Retrying command...");
-            self.device.set(self.create_payload(&dps)).await?;
+            log::warn!(
+                "Command to bulb {} failed ({}), reconnecting in {:?} (attempt {}/{})",
+                self.config.device_id,
+                e,
+                backoff,
+                attempt,
+                self.config.max_retries
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.config.max_backoff);
+
+            result = match self.connect().await {
+                Ok(()) => {
+                    log::info!("Reconnected to bulb {}. Retrying command...", self.config.device_id);
+                    self.counters.reconnected();
+
+                    // the bulb may have reset while we were away, so the retry
+                    // carries the rest of the last state too. It's one write,
+                    // a failure just counts as the next attempt
+                    let mut resend = self.known_dps();
+                    resend.extend(dps.clone());
+                    self.try_send(&resend).await
+                }
+                Err(e) => Err(e),
+            };
         }
 
-        Ok(())
+        match &result {
+            Ok(()) => {
+                self.counters.sent();
+                self.throttle.lock().unwrap().last_payload = Some(dps);
+            }
+            Err(e) => {
+                log::debug!("Giving up on command to bulb {}: {}", self.config.device_id, e);
+                self.counters.failed();
+                self.throttle.lock().unwrap().last_payload = None;
+                self.connected = false;
+            }
+        }
+
+        result
+    }
+
+    /// Send the held back writes once the minimum command interval is up
+    /// A failed flush puts the writes back so the next command carries them.
+    fn schedule_flush(&self, at: Instant) {
+        let device = Arc::downgrade(&self.device);
+        let throttle = self.throttle.clone();
+        let counters = self.counters.clone();
+        let device_id = self.config.device_id.clone();
+        let clock = self.clock;
+
+        tokio::spawn(async move {
+            tokio::time::sleep_until(at.into()).await;
+
+            let dps = {
+                let mut throttle = throttle.lock().unwrap();
+                throttle.flush_scheduled = false;
+                throttle.last_sent = Some(Instant::now());
+                std::mem::take(&mut throttle.pending)
+            };
+            let Some(device) = device.upgrade() else {
+                return;
+            };
+            if dps.is_empty() {
+                return;
+            }
+
+            log::debug!("Sending held back {:?} to bulb {}", dps, device_id);
+            let result = match create_payload(&device_id, &dps, clock()) {
+                Ok(payload) => device.lock().await.set(payload).await.map_err(BulbError::Send),
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(_) => {
+                    counters.sent();
+                    throttle.lock().unwrap().last_payload = Some(dps);
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Failed to send held back command to bulb {} ({}), resending with the next command",
+                        device_id,
+                        e
+                    );
+
+                    let mut throttle = throttle.lock().unwrap();
+                    for (dp, value) in dps {
+                        throttle.pending.entry(dp).or_insert(value);
+                    }
+                }
+            }
+        });
+    }
+
+    async fn try_send(&mut self, dps: &HashMap<String, serde_json::Value>) -> Result<()> {
+        if self.config.dry_run {
+            log::info!("Dry run, not sending {:?} to bulb {}", dps, self.config.device_id);
+            self.sent.push(dps.clone());
+            return Ok(());
+        }
+
+        #[cfg(feature = "fake-device")]
+        if let Some(failures) = &self.config.fake_failures {
+            use std::sync::atomic::Ordering;
+            if failures.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1)).is_ok() {
+                log::debug!("Failing write {:?} to fake bulb {}", dps, self.config.device_id);
+                return Err(BulbError::Timeout);
+            }
+        }
+
+        log::debug!("Sending {:?} to bulb {}", dps, self.config.device_id);
+        let payload = create_payload(&self.config.device_id, dps, (self.clock)())?;
+        self.device.lock().await.set(payload).await.map_err(BulbError::Send)
+    }
+}
+
+impl Drop for BulbController {
+    fn drop(&mut self) {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
+    }
+}
+
+fn check_device_id(device_id: &str) -> Result<()> {
+    if !(10..=32).contains(&device_id.len()) || !device_id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        return Err(BulbError::InvalidConfig(format!(
+            "device_id {:?} should be 10-32 letters and digits",
+            device_id
+        )));
+    }
+    Ok(())
+}
+
+fn check_local_key(local_key: &str) -> Result<()> {
+    // the key is the AES key, so it can't be any other length. Never echo it
+    if local_key.len() != 16 || !local_key.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(BulbError::InvalidConfig(format!(
+            "local_key should be 16 printable characters, got {} characters",
+            local_key.chars().count()
+        )));
+    }
+    Ok(())
+}
+
+fn tuya_device(config: &BulbConfig, ip: IpAddr) -> Result<TuyaDevice> {
+    TuyaDevice::new(config.version.as_str(), &config.device_id, Some(&config.local_key), ip)
+        .map_err(|e| BulbError::InvalidConfig(e.to_string()))
+}
+
+/// Whether `host` looks like a DNS name, so typos in an IP address still fail early
+fn is_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && !host.bytes().all(|b| b.is_ascii_digit() || b == b'.')
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// Look up `host`, preferring an IPv4 address since that's what most bulbs use
+async fn resolve(host: &str) -> Result<IpAddr> {
+    let addrs: Vec<IpAddr> = tokio::net::lookup_host((host, TUYA_PORT))
+        .await
+        .map_err(|e| BulbError::Resolve(format!("{}: {}", host, e)))?
+        .map(|addr| addr.ip())
+        .collect();
+
+    addrs
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or_else(|| addrs.first())
+        .copied()
+        .ok_or_else(|| BulbError::Resolve(format!("{}: no addresses found", host)))
+}
+
+/// Never panics, this runs on the plugin's bulb thread where a panic takes the host down
+fn create_payload(device_id: &str, dps: &HashMap<String, serde_json::Value>, now: SystemTime) -> Result<Payload> {
+    let current_time = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_err(|e| BulbError::Encode(format!("system clock is before 1970: {}", e)))?
+        .as_secs() as u32;
+    let dps = serde_json::to_value(dps).map_err(|e| BulbError::Encode(e.to_string()))?;
+
+    Ok(Payload::Struct(PayloadStruct {
+        dev_id: device_id.to_string(),
+        gw_id: Some(device_id.to_string()),
+        uid: None,
+        t: Some(current_time.to_string()),
+        dp_id: None,
+        dps: Some(dps),
+    }))
+}
+
+fn check_range(name: &'static str, value: impl Into<u32>, max: impl Into<u32>) -> Result<()> {
+    let (value, max) = (value.into(), max.into());
+    if value > max {
+        return Err(BulbError::OutOfRange { name, value, max });
+    }
+    Ok(())
+}
+
+/// Apply a gamma curve to a 0-1000 value
+fn gamma(value: u16, gamma: f32) -> u16 {
+    if gamma == 1.0 {
+        return value;
+    }
+    ((value as f32 / 1000.0).powf(gamma) * 1000.0).round() as u16
+}
+
+fn lerp(from: u16, to: u16, t: f32) -> u16 {
+    (from as f32 + (to as f32 - from as f32) * t).round() as u16
+}
+
+/// Sleep for `duration`, returns true if `cancel` fired first
+async fn sleep_or_cancel(duration: Duration, cancel: &CancellationToken) -> bool {
+    tokio::select! {
+        _ = cancel.cancelled() => true,
+        _ = tokio::time::sleep(duration) => false,
     }
 }
 
-fn hsv_to_hex(h: u16, s: u16, v: u16) -> String {
-    format!("{:04x}{:04x}{:04x}", h, s, v)
+/// Pulls the datapoint object out of a message payload, if it has one
+fn payload_dps(payload: &Payload) -> Option<Map<String, Value>> {
+    let dps = match payload {
+        Payload::Struct(payload) => payload.dps.clone()?,
+        Payload::String(payload) => serde_json::from_str::<Value>(payload).ok()?.get("dps")?.clone(),
+        _ => return None,
+    };
+
+    dps.as_object().cloned()
 }
 
-/// Maps MIDI CC value (0-127) to Hue (0-360)
+/// Maps MIDI CC value (0-127) to Hue (0-359)
+///
+/// 360 is left out since it's the same red as 0, so every step of the
+/// controller is a different color. Values are rounded to the nearest degree,
+/// 0 is 0, 63 is 178, 64 is 181 and 127 is 359.
 pub fn midi_to_hue(midi_value: u8) -> u16 {
-    (midi_value as u16 * 360) / 127
+    ((midi_value as u32 * 359 + 63) / 127) as u16
+}
+
+/// Maps MIDI CC value (0-127) to Saturation (0-1000)
+pub fn midi_to_saturation(midi_value: u8) -> u16 {
+    (midi_value as u32 * 1000 / 127) as u16
+}
+
+/// Maps MIDI CC value or note velocity (0-127) to Brightness (0-1000)
+///
+/// The softest note (velocity 1) is 7, the hardest (127) is 1000.
+pub fn midi_to_brightness(midi_value: u8) -> u16 {
+    (midi_value as u32 * 1000 / 127) as u16
+}
+
+/// Maps a 14-bit pitch bend value (0-16383) to Hue (0-359), rounded like `midi_to_hue`
+///
+/// The wheel at rest (8192) is 180, cyan, so bending down sweeps through green
+/// towards red and bending up through blue and magenta back to red. About 45
+/// bend values share each degree of hue.
+pub fn pitch_bend_to_hue(value: u16) -> u16 {
+    ((value.min(16383) as u32 * 359 + 8191) / 16383) as u16
+}
+
+/// Maps a MIDI note to Hue (0-330), spreading the 12 notes of an octave around
+/// the color wheel with C as red
+pub fn note_to_hue(note: u8) -> u16 {
+    (note % 12) as u16 * 30
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dry_run_config() -> BulbConfig {
+        BulbConfig::builder()
+            .device_id("bf0123456789abcdefgh")
+            .local_key("0123456789abcdef")
+            .ip("127.0.0.1")
+            .dry_run(true)
+            .build()
+            .unwrap()
+    }
+
+    /// A controller that records its writes instead of sending them
+    fn dry_run_controller(config: BulbConfig) -> BulbController {
+        BulbController::new(config).unwrap()
+    }
+
+    /// The value written to `dp` by the most recent write
+    fn last_sent<'a>(controller: &'a BulbController, dp: &str) -> Option<&'a Value> {
+        controller.sent_commands().last()?.get(dp)
+    }
+
+    #[tokio::test]
+    async fn hsv12_color_format() {
+        let mut controller = dry_run_controller(dry_run_config());
+
+        controller.set_color(120, 1000, 1000, true).await.unwrap();
+        let color = last_sent(&controller, "28").and_then(Value::as_str).unwrap();
+        assert_eq!(color.len(), 21);
+        assert_eq!(color, "0007803e803e800000000");
+
+        controller.set_color(120, 1000, 1000, false).await.unwrap();
+        assert_eq!(last_sent(&controller, "28"), Some(&json!("1007803e803e800000000")));
+    }
+
+    #[tokio::test]
+    async fn rgb6_color_format() {
+        let mut config = dry_run_config();
+        config.color_format = ColorFormat::Rgb6;
+        let mut controller = dry_run_controller(config);
+
+        controller.set_color(120, 1000, 1000, true).await.unwrap();
+        let color = last_sent(&controller, "28").and_then(Value::as_str).unwrap();
+        assert_eq!(color.len(), 6);
+        assert_eq!(color, "00ff00");
+    }
+
+    #[tokio::test]
+    async fn set_color_rejects_out_of_range() {
+        let mut controller = dry_run_controller(dry_run_config());
+
+        for (h, s, v, name) in [
+            (361, 0, 0, "Hue"),
+            (u16::MAX, 0, 0, "Hue"),
+            (0, 1001, 0, "Saturation"),
+            (0, u16::MAX, 0, "Saturation"),
+            (0, 0, 1001, "Brightness"),
+            (0, 0, u16::MAX, "Brightness"),
+        ] {
+            let result = controller.set_color(h, s, v, true).await;
+            assert!(
+                matches!(result, Err(BulbError::OutOfRange { name: got, .. }) if got == name),
+                "{:?} gave {:?}",
+                (h, s, v),
+                result
+            );
+        }
+        assert!(controller.sent_commands().is_empty());
+    }
+
+    #[test]
+    fn midi_to_brightness_velocity_ends() {
+        assert_eq!(midi_to_brightness(0), 0);
+        assert_eq!(midi_to_brightness(1), 7);
+        assert_eq!(midi_to_brightness(64), 503);
+        assert_eq!(midi_to_brightness(127), 1000);
+    }
+
+    #[test]
+    fn midi_to_hue_rounding() {
+        assert_eq!(midi_to_hue(0), 0);
+        assert_eq!(midi_to_hue(63), 178);
+        assert_eq!(midi_to_hue(64), 181);
+        assert_eq!(midi_to_hue(127), 359);
+    }
+
+    #[test]
+    fn config_accepts_good_ids_and_keys() {
+        for device_id in [
+            "bf0123456789abcdefgh",
+            "eb1234567890abcdefghij",
+            "0123456789",
+            "ABCDEFabcdef0123456789ABCDEFabcd",
+        ] {
+            assert!(BulbConfig::new(device_id, "0123456789abcdef", "192.168.1.50", "3.3").is_ok(), "{:?}", device_id);
+        }
+        for local_key in ["0123456789abcdef", "a!b@c#d$e%f^g&h*", "{}[]<>?/|~`'\";:="] {
+            assert!(BulbConfig::new("bf0123456789abcdefgh", local_key, "192.168.1.50", "3.3").is_ok(), "{:?}", local_key);
+        }
+        for version in ["3.3", "3.4"] {
+            assert!(BulbConfig::new("bf0123456789abcdefgh", "0123456789abcdef", "192.168.1.50", version).is_ok());
+        }
+    }
+
+    #[test]
+    fn config_rejects_bad_ids_and_keys() {
+        let invalid = |result: Result<BulbConfig>, field: &str| {
+            matches!(result, Err(BulbError::InvalidConfig(message)) if message.contains(field))
+        };
+
+        for device_id in [
+            "",
+            "bf0123456",
+            "bf0123456789abcdefghij0123456789a",
+            "bf01234567-89abcdefg",
+            "bf0123456789 abcdefg",
+            "bf0123456789abcdéfgh",
+        ] {
+            let result = BulbConfig::new(device_id, "0123456789abcdef", "192.168.1.50", "3.3");
+            assert!(invalid(result, "device_id"), "{:?} was accepted", device_id);
+        }
+        for local_key in ["", "0123456789abcde", "0123456789abcdef0", "0123456789 abcde", "0123456789abcdé"] {
+            let result = BulbConfig::new("bf0123456789abcdefgh", local_key, "192.168.1.50", "3.3");
+            assert!(invalid(result, "local_key"), "{:?} was accepted", local_key);
+        }
+        for version in ["", "3.1", "3.5", "3", "v3.3"] {
+            let result = BulbConfig::new("bf0123456789abcdefgh", "0123456789abcdef", "192.168.1.50", version);
+            assert!(invalid(result, "version"), "{:?} was accepted", version);
+        }
+    }
+
+    #[test]
+    fn gamma_curve() {
+        for (value, corrected) in [(0, 0), (250, 47), (500, 218), (750, 531), (1000, 1000)] {
+            assert_eq!(gamma(value, 1.0), value);
+            assert_eq!(gamma(value, 2.2), corrected, "{}", value);
+        }
+    }
+
+    #[tokio::test]
+    async fn set_color_applies_gamma() {
+        let mut raw = dry_run_controller(dry_run_config());
+        raw.set_color(0, 1000, 500, true).await.unwrap();
+        assert_eq!(last_sent(&raw, "28"), Some(&json!("0000003e801f400000000")));
+
+        let mut config = dry_run_config();
+        config.brightness_gamma = 2.2;
+        let mut corrected = dry_run_controller(config);
+        corrected.set_color(0, 1000, 500, true).await.unwrap();
+        assert_eq!(last_sent(&corrected, "28"), Some(&json!("0000003e800da00000000")));
+        // the gamma only changes what's sent, not the color the controller reports
+        assert_eq!(corrected.cached_state().brightness, Some(500));
+    }
+
+    #[tokio::test]
+    async fn set_color_rgb_converts() {
+        let mut controller = dry_run_controller(dry_run_config());
+
+        for (rgb, color) in [
+            ((255, 0, 0), "0000003e803e800000000"),
+            ((0, 255, 0), "0007803e803e800000000"),
+            ((0, 0, 255), "000f003e803e800000000"),
+            // white has no saturation, black no brightness
+            ((255, 255, 255), "00000000003e800000000"),
+            ((0, 0, 0), "000000000000000000000"),
+            ((128, 128, 128), "00000000001f600000000"),
+        ] {
+            controller.set_color_rgb(rgb.0, rgb.1, rgb.2, true).await.unwrap();
+            assert_eq!(last_sent(&controller, "28"), Some(&json!(color)), "{:?}", rgb);
+        }
+    }
+
+    #[tokio::test]
+    async fn set_color_hex_parses() {
+        let mut controller = dry_run_controller(dry_run_config());
+
+        controller.set_color_hex("#FF8800", true).await.unwrap();
+        assert_eq!(last_sent(&controller, "28"), Some(&json!("0002003e803e800000000")));
+
+        // the # is optional and case doesn't matter
+        controller.set_color_hex("0088ff", true).await.unwrap();
+        assert_eq!(last_sent(&controller, "28"), Some(&json!("000d003e803e800000000")));
+    }
+
+    #[tokio::test]
+    async fn set_color_hex_rejects_malformed() {
+        let mut controller = dry_run_controller(dry_run_config());
+
+        // shorthand is rejected rather than guessed at
+        for hex in ["#f80", "f80", "#ff88", "#ff880000", "zzzzzz", "#ff88zz", "not a color"] {
+            let result = controller.set_color_hex(hex, true).await;
+            assert!(matches!(result, Err(BulbError::ParseColor(_))), "{:?} gave {:?}", hex, result);
+        }
+        assert!(controller.sent_commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn toggle_flips_known_power() {
+        let mut controller = dry_run_controller(dry_run_config());
+
+        controller.set_power(true).await.unwrap();
+        controller.toggle().await.unwrap();
+        assert_eq!(last_sent(&controller, "20"), Some(&json!(false)));
+        controller.toggle().await.unwrap();
+        assert_eq!(last_sent(&controller, "20"), Some(&json!(true)));
+    }
+
+    #[tokio::test]
+    async fn toggle_without_known_power_fails() {
+        // a dry run has no bulb to ask, so the power state stays unknown
+        let mut controller = dry_run_controller(dry_run_config());
+
+        assert!(matches!(controller.toggle().await, Err(BulbError::Protocol(_))));
+        assert!(controller.sent_commands().is_empty());
+    }
+
+    #[tokio::test]
+    async fn known_dps_follow_work_mode() {
+        let mut controller = dry_run_controller(dry_run_config());
+
+        controller.set_color(120, 1000, 1000, true).await.unwrap();
+        let dps = controller.known_dps();
+        assert_eq!(dps.get("21"), Some(&json!("colour")));
+        assert!(dps.contains_key("28"));
+
+        controller.set_white(800, 500).await.unwrap();
+        let dps = controller.known_dps();
+        assert_eq!(dps.get("21"), Some(&json!("white")));
+        assert_eq!(dps.get("22"), Some(&json!(800)));
+        assert_eq!(dps.get("23"), Some(&json!(500)));
+        assert!(!dps.contains_key("28"));
+    }
+
+    #[tokio::test]
+    async fn known_dps_leave_out_color_in_white_mode() {
+        let mut controller = dry_run_controller(dry_run_config());
+        controller.set_color(120, 1000, 1000, true).await.unwrap();
+
+        // switched to white from the Tuya app, the white values never reported
+        let mut report = Map::new();
+        report.insert("21".to_string(), json!("white"));
+        controller.apply_dps(&report);
+
+        let dps = controller.known_dps();
+        assert_eq!(dps.get("21"), Some(&json!("white")));
+        assert!(!dps.contains_key("28"));
+    }
+
+    #[tokio::test]
+    async fn set_power_sends_only_power() {
+        let mut controller = dry_run_controller(dry_run_config());
+
+        controller.set_power(false).await.unwrap();
+        assert_eq!(controller.sent_commands(), [HashMap::from([("20".to_string(), json!(false))])]);
+    }
+
+    #[tokio::test]
+    async fn set_color_leaves_power_alone() {
+        let mut controller = dry_run_controller(dry_run_config());
+        controller.set_power(false).await.unwrap();
+
+        controller.set_color(120, 1000, 1000, true).await.unwrap();
+        let dps = controller.sent_commands().last().unwrap();
+        assert!(!dps.contains_key("20"));
+        assert_eq!(dps.get("21"), Some(&json!("colour")));
+        assert!(dps.contains_key("28"));
+        assert_eq!(controller.cached_state().power, Some(false));
+    }
+
+    #[tokio::test]
+    async fn set_white_and_set_scene_leave_power_alone() {
+        let mut controller = dry_run_controller(dry_run_config());
+        controller.set_power(false).await.unwrap();
+
+        controller.set_white(800, 500).await.unwrap();
+        let dps = controller.sent_commands().last().unwrap();
+        assert!(!dps.contains_key("20"));
+        assert_eq!(dps.get("21"), Some(&json!("white")));
+
+        controller.set_scene(Scene::Reading).await.unwrap();
+        let dps = controller.sent_commands().last().unwrap();
+        assert!(!dps.contains_key("20"));
+        assert_eq!(dps.get("21"), Some(&json!("scene")));
+        assert_eq!(controller.cached_state().power, Some(false));
+    }
+
+    #[tokio::test]
+    async fn cancelled_fade_stops_promptly() {
+        let mut controller = dry_run_controller(dry_run_config());
+        controller.set_color(0, 1000, 0, true).await.unwrap();
+
+        let cancel = CancellationToken::new();
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                cancel.cancel();
+            }
+        });
+        let started = Instant::now();
+        controller
+            .fade_to_cancellable(0, 1000, 1000, Duration::from_secs(10), &cancel)
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
+
+        // left at the last step sent, part way there
+        let last = last_sent(&controller, "28").and_then(Value::as_str).and_then(decode_hsv).unwrap();
+        assert!(last.2 > 0 && last.2 < 1000, "stopped at {:?}", last);
+        let state = controller.cached_state();
+        assert_eq!((state.hue, state.saturation, state.brightness), (Some(last.0), Some(last.1), Some(last.2)));
+    }
+
+    #[tokio::test]
+    async fn strobe_restores_previous_state() {
+        let mut controller = dry_run_controller(dry_run_config());
+        controller.set_color(0, 1000, 1000, true).await.unwrap();
+        controller.set_power(true).await.unwrap();
+
+        let timing = StrobeTiming { on_ms: 1, off_ms: 1, cycles: 2 };
+        controller.strobe(120, 1000, 1000, timing, &CancellationToken::new()).await.unwrap();
+
+        let powers: Vec<&Value> = controller.sent_commands()[2..].iter().filter_map(|dps| dps.get("20")).collect();
+        assert_eq!(powers, [&json!(true), &json!(false), &json!(true), &json!(false), &json!(true)]);
+        let state = controller.cached_state();
+        assert_eq!((state.power, state.hue), (Some(true), Some(0)));
+    }
+
+    #[tokio::test]
+    async fn get_status_needs_power_and_color() {
+        let mut controller = dry_run_controller(dry_run_config());
+        assert!(matches!(controller.get_status().await, Err(BulbError::Protocol(_))));
+
+        controller.set_power(false).await.unwrap();
+        assert!(matches!(controller.get_status().await, Err(BulbError::Protocol(_))));
+
+        controller.set_color(120, 500, 250, true).await.unwrap();
+        let status = controller.get_status().await.unwrap();
+        assert_eq!(status, BulbStatus { power: false, hue: 120, saturation: 500, brightness: 250 });
+    }
+
+    #[tokio::test]
+    async fn set_white_sends_white_dps() {
+        let mut controller = dry_run_controller(dry_run_config());
+
+        controller.set_white(800, 300).await.unwrap();
+        let dps = controller.sent_commands().last().unwrap();
+        assert_eq!(dps.get("21"), Some(&json!("white")));
+        assert_eq!(dps.get("22"), Some(&json!(800)));
+        assert_eq!(dps.get("23"), Some(&json!(300)));
+        assert!(!dps.contains_key("28"));
+    }
+
+    #[tokio::test]
+    async fn work_mode_flips_between_color_and_white() {
+        let mut controller = dry_run_controller(dry_run_config());
+
+        controller.set_color(120, 1000, 1000, true).await.unwrap();
+        assert_eq!(last_sent(&controller, "21"), Some(&json!("colour")));
+        controller.set_white(800, 300).await.unwrap();
+        assert_eq!(last_sent(&controller, "21"), Some(&json!("white")));
+        controller.set_color(240, 1000, 1000, true).await.unwrap();
+        assert_eq!(last_sent(&controller, "21"), Some(&json!("colour")));
+        controller.set_white(500, 700).await.unwrap();
+        assert_eq!(last_sent(&controller, "21"), Some(&json!("white")));
+        assert_eq!(controller.cached_state().work_mode, Some(WorkMode::White));
+    }
+
+    fn before_1970() -> SystemTime {
+        SystemTime::UNIX_EPOCH - Duration::from_secs(1)
+    }
+
+    #[test]
+    fn create_payload_rejects_clock_before_1970() {
+        let dps = HashMap::from([("20".to_string(), json!(true))]);
+
+        assert!(create_payload("bf0123456789abcdefgh", &dps, SystemTime::now()).is_ok());
+        let result = create_payload("bf0123456789abcdefgh", &dps, before_1970());
+        assert!(matches!(result, Err(BulbError::Encode(_))), "{:?}", result.err());
+    }
+
+    #[tokio::test]
+    async fn encode_error_is_returned_without_reconnecting() {
+        // not a dry run, so the payload is built, but it fails before anything is sent
+        let config = BulbConfig::builder()
+            .device_id("bf0123456789abcdefgh")
+            .local_key("0123456789abcdef")
+            .ip("127.0.0.1")
+            .build()
+            .unwrap();
+        let mut controller = BulbController::new(config).unwrap();
+        controller.set_max_retries(3);
+        controller.clock = before_1970;
+
+        let result = controller.set_power(true).await;
+        assert!(matches!(result, Err(BulbError::Encode(_))), "{:?}", result.err());
+        let metrics = controller.metrics();
+        assert_eq!(metrics.reconnects, 0);
+        assert_eq!(metrics.command_failures, 1);
+    }
 }
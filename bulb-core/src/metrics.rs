@@ -0,0 +1,81 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters shared between a controller and its background flush task
+#[derive(Default)]
+pub(crate) struct Counters {
+    commands_sent: AtomicU64,
+    command_failures: AtomicU64,
+    reconnects: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn sent(&self) {
+        self.commands_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn failed(&self) {
+        self.command_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> BulbMetrics {
+        BulbMetrics {
+            commands_sent: self.commands_sent.load(Ordering::Relaxed),
+            command_failures: self.command_failures.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of a controller's counters, see `BulbController::metrics`
+///
+/// The counters start at zero when the controller is created and only go up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BulbMetrics {
+    /// Writes the bulb accepted, including held back writes sent later
+    pub commands_sent: u64,
+    /// Commands that still failed after all retries
+    pub command_failures: u64,
+    /// Successful reconnects after a failed command
+    pub reconnects: u64,
+}
+
+impl BulbMetrics {
+    /// Format the counters in the Prometheus text exposition format,
+    /// labelled with `device_id`
+    ///
+    /// Each call includes the `# TYPE` lines, so combine several bulbs with
+    /// `prometheus_text` instead of concatenating the output.
+    pub fn to_prometheus(&self, device_id: &str) -> String {
+        prometheus_text([(device_id, *self)])
+    }
+}
+
+/// Format the counters of several bulbs as one Prometheus text exposition
+pub fn prometheus_text<'a>(bulbs: impl IntoIterator<Item = (&'a str, BulbMetrics)>) -> String {
+    let bulbs: Vec<_> = bulbs.into_iter().collect();
+    let counters: [(&str, &str, fn(&BulbMetrics) -> u64); 3] = [
+        ("bulb_commands_sent_total", "Writes the bulb accepted", |m| m.commands_sent),
+        ("bulb_command_failures_total", "Commands that failed after all retries", |m| m.command_failures),
+        ("bulb_reconnects_total", "Reconnects after a failed command", |m| m.reconnects),
+    ];
+
+    let mut out = String::new();
+    for (name, help, value) in counters {
+        // writing to a String can't fail
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} counter", name).unwrap();
+        for (device_id, metrics) in &bulbs {
+            writeln!(out, "{}{{device_id=\"{}\"}} {}", name, escape_label(device_id), value(metrics)).unwrap();
+        }
+    }
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
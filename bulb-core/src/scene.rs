@@ -0,0 +1,52 @@
+/// A preset scene written to the scene DP (`BulbConfig::scene_dp`)
+///
+/// Scenes are encoded as a 2 digit scene number followed by one or more
+/// units. Each unit is 26 hex digits:
+///
+/// - switch interval (2) and transition speed (2)
+/// - change mode (2): 00 static, 01 jump, 02 gradient
+/// - hue (4), saturation (4) and value (4), as in `hsv_to_hex`
+/// - white brightness (4) and temperature (4)
+///
+/// The built-ins match the defaults of Tuya's own app. `Raw` is written as is,
+/// for scenes copied from the app or designed by hand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scene {
+    Night,
+    Reading,
+    Working,
+    Leisure,
+    /// Jumps between red, green and blue
+    Party,
+    Raw(String),
+}
+
+impl Scene {
+    pub fn encode(&self) -> String {
+        match self {
+            Scene::Night => format!("00{}", unit(0x0e, 0x0d, 0, (0, 0, 0), (200, 0))),
+            Scene::Reading => format!("01{}", unit(0x0e, 0x0d, 0, (0, 0, 0), (1000, 500))),
+            Scene::Working => format!("02{}", unit(0x0e, 0x0d, 0, (0, 0, 0), (1000, 1000))),
+            Scene::Leisure => format!("03{}", unit(0x0e, 0x0d, 0, (0, 0, 0), (500, 0))),
+            Scene::Party => format!(
+                "04{}{}{}",
+                unit(0x46, 0x46, 1, (0, 1000, 1000), (0, 0)),
+                unit(0x46, 0x46, 1, (120, 1000, 1000), (0, 0)),
+                unit(0x46, 0x46, 1, (240, 1000, 1000), (0, 0)),
+            ),
+            Scene::Raw(data) => data.clone(),
+        }
+    }
+}
+
+fn unit(interval: u8, transition: u8, mode: u8, (h, s, v): (u16, u16, u16), (brightness, temperature): (u16, u16)) -> String {
+    format!(
+        "{:02x}{:02x}{:02x}{}{:04x}{:04x}",
+        interval,
+        transition,
+        mode,
+        crate::hsv_to_hex(h, s, v),
+        brightness,
+        temperature
+    )
+}
@@ -0,0 +1,281 @@
+use crate::{
+    BulbCommand, BulbVstParams, ColorTarget, ConnectionSettings, ConnectionState, ConnectionStatus, Side, MAX_PRESETS,
+    NO_CC,
+};
+use crossbeam_channel::Sender;
+use nih_plug::prelude::*;
+use nih_plug_egui::egui::{self, ecolor::Hsva, Color32, Mesh, Sense, Stroke};
+use nih_plug_egui::{create_egui_editor, EguiState};
+use std::f32::consts::TAU;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Number of triangles the color wheel is drawn with
+const WHEEL_SEGMENTS: u32 = 96;
+
+const WHEEL_DIAMETER: f32 = 220.0;
+
+/// Text being edited, only applied when its button is pressed
+#[derive(Default)]
+struct EditBuffers {
+    connection: ConnectionSettings,
+    right_connection: ConnectionSettings,
+    preset_name: String,
+}
+
+pub(crate) fn default_state() -> Arc<EguiState> {
+    EguiState::from_size(340, 360)
+}
+
+pub(crate) fn create(
+    params: Arc<BulbVstParams>,
+    statuses: [Arc<Mutex<ConnectionStatus>>; 2],
+    command_tx: Sender<(Side, BulbCommand)>,
+) -> Option<Box<dyn Editor>> {
+    create_egui_editor(
+        params.editor_state.clone(),
+        EditBuffers::default(),
+        {
+            let params = params.clone();
+            move |_, buffers| {
+                // edit a copy so half typed settings don't reconnect on every keystroke
+                buffers.connection = params.connection.read().unwrap_or_else(PoisonError::into_inner).clone();
+                buffers.right_connection = params.right_connection.read().unwrap_or_else(PoisonError::into_inner).clone();
+            }
+        },
+        move |egui_ctx, setter, buffers| {
+            egui::CentralPanel::default().show(egui_ctx, |ui| {
+                ui.horizontal(|ui| {
+                    color_wheel(ui, &params, setter);
+                    brightness_slider(ui, &params, setter);
+                });
+
+                ui.separator();
+                let [status, right_status] = &statuses;
+                status_line(ui, &ConnectionStatus::get(status));
+                if params.two_bulbs.value() {
+                    ui.horizontal(|ui| {
+                        ui.label("Right:");
+                        status_line(ui, &ConnectionStatus::get(right_status));
+                    });
+                }
+
+                ui.collapsing("Presets", |ui| presets(ui, &params, setter, &mut buffers.preset_name));
+
+                ui.collapsing("MIDI Learn", |ui| midi_learn(ui, &params));
+
+                let settings = &mut buffers.connection;
+                ui.collapsing("Bulb", |ui| {
+                    if connection_form(ui, "connection", settings) {
+                        *params.connection.write().unwrap_or_else(PoisonError::into_inner) = settings.clone();
+                        command_tx.try_send((Side::Left, BulbCommand::Configure(settings.clone()))).ok();
+                    }
+                });
+
+                let settings = &mut buffers.right_connection;
+                ui.collapsing("Right Bulb", |ui| {
+                    // connecting with an empty device ID disconnects the right bulb
+                    if connection_form(ui, "right_connection", settings) {
+                        *params.right_connection.write().unwrap_or_else(PoisonError::into_inner) = settings.clone();
+                        command_tx.try_send((Side::Right, BulbCommand::Configure(settings.clone()))).ok();
+                    }
+                });
+            });
+        },
+    )
+}
+
+/// The connection settings and a Connect button, returns true when it's pressed
+fn connection_form(ui: &mut egui::Ui, id: &str, settings: &mut ConnectionSettings) -> bool {
+    egui::Grid::new(id).num_columns(2).show(ui, |ui| {
+        ui.label("Device ID");
+        ui.text_edit_singleline(&mut settings.device_id);
+        ui.end_row();
+
+        ui.label("Local key");
+        ui.add(egui::TextEdit::singleline(&mut settings.local_key).password(true));
+        ui.end_row();
+
+        ui.label("IP");
+        ui.text_edit_singleline(&mut settings.ip);
+        ui.end_row();
+
+        ui.label("Version");
+        ui.text_edit_singleline(&mut settings.version);
+        ui.end_row();
+
+        ui.label("Share");
+        ui.checkbox(&mut settings.share, "")
+            .on_hover_text("Use one connection with the other instances driving this bulb");
+        ui.end_row();
+    });
+
+    ui.button("Connect").clicked()
+}
+
+/// Hue around the wheel and saturation from the center out, shaded at the
+/// current brightness
+fn color_wheel(ui: &mut egui::Ui, params: &BulbVstParams, setter: &ParamSetter) {
+    let (rect, response) = ui.allocate_exact_size(egui::vec2(WHEEL_DIAMETER, WHEEL_DIAMETER), Sense::click_and_drag());
+    let center = rect.center();
+    let radius = WHEEL_DIAMETER / 2.0;
+    let brightness = params.brightness.value();
+
+    if let Some(pos) = response.interact_pointer_pos() {
+        let offset = pos - center;
+        let hue = (-offset.y).atan2(offset.x).rem_euclid(TAU) / TAU;
+        let saturation = (offset.length() / radius).min(1.0);
+
+        // go through the setter so hosts record the change as automation
+        if response.drag_started() || response.clicked() {
+            setter.begin_set_parameter(&params.hue);
+            setter.begin_set_parameter(&params.saturation);
+        }
+        if response.dragged() || response.clicked() {
+            setter.set_parameter(&params.hue, hue);
+            setter.set_parameter(&params.saturation, saturation);
+        }
+    }
+    if response.drag_stopped() || response.clicked() {
+        setter.end_set_parameter(&params.hue);
+        setter.end_set_parameter(&params.saturation);
+    }
+
+    // a fan of triangles, fading from white in the middle to full saturation at the edge
+    let mut mesh = Mesh::default();
+    mesh.colored_vertex(center, Color32::from(Hsva::new(0.0, 0.0, brightness, 1.0)));
+    for i in 0..=WHEEL_SEGMENTS {
+        let hue = i as f32 / WHEEL_SEGMENTS as f32;
+        let angle = hue * TAU;
+        let pos = center + radius * egui::vec2(angle.cos(), -angle.sin());
+
+        mesh.colored_vertex(pos, Color32::from(Hsva::new(hue, 1.0, brightness, 1.0)));
+        if i > 0 {
+            mesh.add_triangle(0, i, i + 1);
+        }
+    }
+    ui.painter().add(mesh);
+
+    let angle = params.hue.value() * TAU;
+    let marker = center + params.saturation.value() * radius * egui::vec2(angle.cos(), -angle.sin());
+    ui.painter().circle_stroke(marker, 6.0, Stroke::new(2.0, Color32::BLACK));
+    ui.painter().circle_stroke(marker, 4.0, Stroke::new(1.5, Color32::WHITE));
+}
+
+/// A colored dot and what it means, hovering shows the last error
+fn status_line(ui: &mut egui::Ui, status: &ConnectionStatus) {
+    let (color, text) = match status.state {
+        ConnectionState::Unconfigured => (Color32::GRAY, "No bulb configured"),
+        ConnectionState::Connecting => (Color32::YELLOW, "Connecting..."),
+        ConnectionState::Connected => (Color32::GREEN, "Connected"),
+        ConnectionState::Disconnected => (Color32::RED, "Reconnecting..."),
+    };
+
+    let response = ui
+        .horizontal(|ui| {
+            let (rect, _) = ui.allocate_exact_size(egui::vec2(12.0, 12.0), Sense::hover());
+            ui.painter().circle_filled(rect.center(), 5.0, color);
+            ui.label(text);
+        })
+        .response;
+
+    if let Some(error) = &status.last_error {
+        response.on_hover_text(error);
+    }
+}
+
+/// Recall buttons for the stored presets, and saving the current color as a new one
+///
+/// Presets keep their Recall number, so deleting one leaves a gap that the
+/// next save fills.
+fn presets(ui: &mut egui::Ui, params: &BulbVstParams, setter: &ParamSetter, name: &mut String) {
+    let mut remove = None;
+    for (index, preset) in params.presets.read().unwrap_or_else(PoisonError::into_inner).iter().enumerate() {
+        let Some((preset_name, _)) = preset else {
+            continue;
+        };
+        ui.horizontal(|ui| {
+            ui.label(format!("{}.", index + 1));
+            // through the Recall param, so recalling from here can be automated the same way
+            if ui.button(preset_name).clicked() {
+                setter.begin_set_parameter(&params.recall);
+                setter.set_parameter(&params.recall, index as i32 + 1);
+                setter.end_set_parameter(&params.recall);
+            }
+            if ui.small_button("x").on_hover_text("Delete").clicked() {
+                remove = Some(index);
+            }
+        });
+    }
+
+    if let Some(index) = remove {
+        params.delete_preset(index);
+    }
+
+    ui.horizontal(|ui| {
+        ui.text_edit_singleline(name);
+
+        let full = params.presets.read().unwrap_or_else(PoisonError::into_inner).iter().flatten().count() >= MAX_PRESETS;
+        let save = ui.add_enabled(!full && !name.trim().is_empty(), egui::Button::new("Save"));
+        if save.clicked() {
+            params.save_preset(name.trim().to_string(), params.color());
+            name.clear();
+        }
+    });
+}
+
+/// Bind the color params to CCs by moving a knob after pressing Learn
+fn midi_learn(ui: &mut egui::Ui, params: &BulbVstParams) {
+    egui::Grid::new("midi_learn").num_columns(3).show(ui, |ui| {
+        for (index, target) in ColorTarget::ALL.into_iter().enumerate() {
+            ui.label(target.name());
+
+            let binding = params.cc_binding(target);
+            match binding.load(Ordering::Relaxed) {
+                NO_CC => ui.label("Unmapped"),
+                cc => ui.label(format!("CC {}", cc)),
+            };
+
+            ui.horizontal(|ui| {
+                if params.learning() == Some(target) {
+                    if ui.button("Listening...").clicked() {
+                        params.learning.store(0, Ordering::Relaxed);
+                    }
+                } else if ui.button("Learn").clicked() {
+                    params.learning.store(index as u8 + 1, Ordering::Relaxed);
+                }
+
+                if ui.button("Clear").clicked() {
+                    binding.store(NO_CC, Ordering::Relaxed);
+                }
+            });
+            ui.end_row();
+        }
+    });
+}
+
+fn brightness_slider(ui: &mut egui::Ui, params: &BulbVstParams, setter: &ParamSetter) {
+    let mut brightness = params.brightness.value();
+    let response = ui.add(
+        egui::Slider::new(&mut brightness, 0.0..=1.0)
+            .vertical()
+            .show_value(false),
+    );
+
+    if response.drag_started() {
+        setter.begin_set_parameter(&params.brightness);
+    }
+    if response.changed() {
+        if response.dragged() {
+            setter.set_parameter(&params.brightness, brightness);
+        } else {
+            // clicks and keyboard changes are a gesture of their own
+            setter.begin_set_parameter(&params.brightness);
+            setter.set_parameter(&params.brightness, brightness);
+            setter.end_set_parameter(&params.brightness);
+        }
+    }
+    if response.drag_stopped() {
+        setter.end_set_parameter(&params.brightness);
+    }
+}
@@ -1,49 +1,306 @@
-use bulb_core::{BulbConfig, BulbController};
-use crossbeam_channel::{bounded, Receiver, Sender};
+use bulb_core::{kelvin_to_temperature, note_to_hue, BulbConfig, BulbController};
+use crossbeam_channel::{bounded, Receiver, RecvTimeoutError, Sender};
 use nih_plug::prelude::*;
-use std::sync::Arc;
+use nih_plug_egui::EguiState;
+use serde::{Deserialize, Serialize};
+use shared::SharedBulb;
+use sysex::BulbSysEx;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+mod editor;
+mod shared;
+mod sysex;
+
+/// Minimum time between color commands sent from the audio thread
+///
+/// At small buffer sizes `process` runs hundreds of times a second, far faster
+/// than the bulb can follow. Sends closer together than this are skipped, and
+/// since a skipped value isn't recorded as sent the newest value still goes
+/// out once the interval is up. This keeps the command channel from filling
+/// up, which would stall the audio thread.
+const MIN_SEND_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Number of presets that can be stored, and the range of the Recall param
+const MAX_PRESETS: usize = 16;
+
+/// Stored in the CC bindings when a param isn't bound to a CC
+const NO_CC: u8 = u8::MAX;
+
+/// How long the host waits on unload for the bulb thread to finish
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How often the bulb thread wakes up while waiting, so a changed Idle
+/// Timeout applies to the wait already running
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which bulb a command is for, the right one is only driven with Two Bulbs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Left,
+    Right,
+}
 
 enum BulbCommand {
     SetHSV(u16, u16, u16, bool),
+    FadeHSV(u16, u16, u16, Duration),
+    SetPower(bool),
+    Configure(ConnectionSettings),
+    SetWhite(u16, u16),
+    /// Stops the bulb thread, whichever side it's sent for
+    Shutdown,
 }
 
 pub struct BulbVst {
     params: Arc<BulbVstParams>,
-    command_tx: Sender<BulbCommand>,
-    _bulb_thread: Option<std::thread::JoinHandle<()>>,
+    command_tx: Sender<(Side, BulbCommand)>,
+    /// Commands that didn't fit in the channel, logged by the bulb thread
+    dropped_commands: Arc<AtomicU32>,
+    /// Written by the bulb thread and shown in the editor, never touched from `process`
+    status: Arc<Mutex<ConnectionStatus>>,
+    right_status: Arc<Mutex<ConnectionStatus>>,
+    bulb_thread: Option<std::thread::JoinHandle<()>>,
     last_hue: u16,
     last_saturation: u16,
     last_brightness: u16,
+    last_temperature: u16,
+    /// The power and Two Bulbs params as of the last power command sent
+    last_power: Option<(bool, bool)>,
+    last_white_mode: Option<bool>,
+    last_send: Option<Instant>,
+    last_recall: i32,
+    last_enabled: bool,
+    sample_rate: f32,
+    /// Smoothed RMS of the input, for Audio Reactive mode
+    envelope: f32,
+    /// Hue and brightness from the last note played, used while MIDI Control is on
+    note_color: Option<(u16, u16)>,
+    /// The color from the last SysEx message and the color params when it
+    /// arrived, dropped once one of the params moves
+    sysex_color: Option<(ColorPreset, ColorPreset)>,
+    /// The last value of each bound CC, indexed by `ColorTarget`
+    cc_values: [Option<f32>; 3],
+    last_connection: Option<ConnectionSettings>,
+    /// The last color or white setting sent to the right bulb, `None` to resend
+    last_right_color: Option<ColorPreset>,
+    last_right_white: Option<(u16, u16)>,
+    last_right_connection: Option<ConnectionSettings>,
+}
+
+/// Which bulb to talk to, saved with the plugin state
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct ConnectionSettings {
+    device_id: String,
+    local_key: String,
+    ip: String,
+    version: String,
+    /// Reuse the connection of other instances driving the same bulb
+    #[serde(default)]
+    share: bool,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self {
+            device_id: String::new(),
+            local_key: String::new(),
+            ip: String::new(),
+            version: "3.3".to_string(),
+            share: false,
+        }
+    }
+}
+
+/// A stored color, in the ranges `BulbController::set_color` takes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct ColorPreset {
+    hue: u16,
+    saturation: u16,
+    brightness: u16,
+}
+
+/// The params that can be bound to a MIDI CC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorTarget {
+    Hue,
+    Saturation,
+    Brightness,
+}
+
+impl ColorTarget {
+    const ALL: [ColorTarget; 3] = [ColorTarget::Hue, ColorTarget::Saturation, ColorTarget::Brightness];
+
+    fn name(self) -> &'static str {
+        match self {
+            ColorTarget::Hue => "Hue",
+            ColorTarget::Saturation => "Saturation",
+            ColorTarget::Brightness => "Brightness",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum ConnectionState {
+    #[default]
+    Unconfigured,
+    Connecting,
+    Connected,
+    /// The last command failed, the next one will try to reconnect
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ConnectionStatus {
+    state: ConnectionState,
+    /// Kept after a reconnect so it can still be looked up
+    last_error: Option<String>,
+}
+
+impl ConnectionStatus {
+    // a panic while the lock was held can't leave the status half written,
+    // so a poisoned lock is used as is rather than taking the thread down too
+    fn update(status: &Mutex<ConnectionStatus>, state: ConnectionState, error: Option<String>) {
+        let mut status = status.lock().unwrap_or_else(PoisonError::into_inner);
+        status.state = state;
+        if error.is_some() {
+            status.last_error = error;
+        }
+    }
+
+    /// A copy of the status, for the editor to show
+    fn get(status: &Mutex<ConnectionStatus>) -> ConnectionStatus {
+        status.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
 }
 
 #[derive(Params)]
 struct BulbVstParams {
+    /// Nothing is sent to the bulb while this is off, it keeps its last state
+    #[id = "enabled"]
+    pub enabled: BoolParam,
     #[id = "hue"]
     pub hue: FloatParam,
     #[id = "saturation"]
     pub saturation: FloatParam,
     #[id = "brightness"]
     pub brightness: FloatParam,
+    /// Brightness is remapped onto this range, so 0% doesn't have to mean off
+    #[id = "bright_min"]
+    pub bright_min: FloatParam,
+    #[id = "bright_max"]
+    pub bright_max: FloatParam,
+    #[id = "invert_brightness"]
+    pub invert_brightness: BoolParam,
     #[id = "immediate"]
     pub immediate: BoolParam,
+    #[id = "transition"]
+    pub transition: FloatParam,
+    #[id = "power"]
+    pub power: BoolParam,
+    #[id = "white_mode"]
+    pub white_mode: BoolParam,
+    #[id = "temperature"]
+    pub temperature: FloatParam,
+    /// Drive brightness from the input level instead of the params
+    #[id = "audio_reactive"]
+    pub audio_reactive: BoolParam,
+    #[id = "attack"]
+    pub attack: FloatParam,
+    #[id = "release"]
+    pub release: FloatParam,
+    /// Rotates every hue, whether it comes from automation, notes or CCs
+    #[id = "hue_offset"]
+    pub hue_offset: FloatParam,
+    /// Rotate the hue once per cycle in time with the host while it's playing
+    #[id = "beat_sync"]
+    pub beat_sync: BoolParam,
+    #[id = "cycle_bars"]
+    pub cycle_bars: FloatParam,
+    /// Take hue and brightness from played notes instead of the params
+    #[id = "midi_control"]
+    pub midi_control: BoolParam,
+    /// Only notes and CCs on this channel are listened to, 0 listens to all of them
+    #[id = "midi_channel"]
+    pub midi_channel: IntParam,
+    /// Recalls the preset at this position, 0 recalls nothing
+    #[id = "recall"]
+    pub recall: IntParam,
+    /// Minutes without any command before the bulbs are switched off, 0 never switches them off
+    #[id = "idle_timeout"]
+    pub idle_timeout: IntParam,
+    /// Drive a second bulb from the Right params, power and white mode are shared
+    #[id = "two_bulbs"]
+    pub two_bulbs: BoolParam,
+    #[id = "right_hue"]
+    pub right_hue: FloatParam,
+    #[id = "right_saturation"]
+    pub right_saturation: FloatParam,
+    #[id = "right_brightness"]
+    pub right_brightness: FloatParam,
+    /// By Recall position, deleted ones leave an empty slot so the rest keep theirs
+    #[persist = "presets"]
+    presets: RwLock<Vec<Option<(String, ColorPreset)>>>,
+    /// CC numbers the color params are bound to, or `NO_CC`
+    #[persist = "hue-cc"]
+    hue_cc: AtomicU8,
+    #[persist = "saturation-cc"]
+    saturation_cc: AtomicU8,
+    #[persist = "brightness-cc"]
+    brightness_cc: AtomicU8,
+    /// Set by the editor to bind the next incoming CC, `ColorTarget` index plus one or 0 when not learning
+    learning: AtomicU8,
+    #[persist = "connection"]
+    connection: RwLock<ConnectionSettings>,
+    /// An empty device ID leaves the right bulb unconfigured
+    #[persist = "right-connection"]
+    right_connection: RwLock<ConnectionSettings>,
+    #[persist = "editor-state"]
+    editor_state: Arc<EguiState>,
 }
 
 impl Default for BulbVst {
     fn default() -> Self {
         let (command_tx, command_rx) = bounded(100);
+        let dropped_commands = Arc::new(AtomicU32::new(0));
+        let status = Arc::new(Mutex::new(ConnectionStatus::default()));
+        let right_status = Arc::new(Mutex::new(ConnectionStatus::default()));
+        let params = Arc::new(BulbVstParams::default());
 
         // use separate thread for bulb comms, since vst must be real-time safe
-        let bulb_thread = std::thread::spawn(move || {
-            bulb_controller_thread(command_rx);
+        let bulb_thread = std::thread::spawn({
+            let params = params.clone();
+            let dropped_commands = dropped_commands.clone();
+            let statuses = [status.clone(), right_status.clone()];
+            move || bulb_controller_thread(params, command_rx, dropped_commands, statuses)
         });
 
         Self {
-            params: Arc::new(BulbVstParams::default()),
+            params,
             command_tx,
-            _bulb_thread: Some(bulb_thread),
+            dropped_commands,
+            status,
+            right_status,
+            bulb_thread: Some(bulb_thread),
             last_hue: u16::MAX,
             last_saturation: u16::MAX,
             last_brightness: u16::MAX,
+            last_temperature: u16::MAX,
+            last_power: None,
+            last_white_mode: None,
+            last_send: None,
+            last_recall: 0,
+            last_enabled: true,
+            sample_rate: 44100.0,
+            envelope: 0.0,
+            note_color: None,
+            sysex_color: None,
+            cc_values: [None; 3],
+            last_connection: None,
+            last_right_color: None,
+            last_right_white: None,
+            last_right_connection: None,
         }
     }
 }
@@ -51,6 +308,7 @@ impl Default for BulbVst {
 impl Default for BulbVstParams {
     fn default() -> Self {
         Self {
+            enabled: BoolParam::new("Enabled", true),
             hue: FloatParam::new(
                 "Hue",
                 0.0,
@@ -85,7 +343,7 @@ impl Default for BulbVstParams {
                 string.trim_end_matches("%")
                     .parse::<f32>()
                     .ok()
-                    .map(|degrees| degrees / 100.0)
+                    .map(|percent| percent / 100.0)
             })),
             brightness: FloatParam::new(
                 "Brightness",
@@ -103,9 +361,328 @@ impl Default for BulbVstParams {
                 string.trim_end_matches("%")
                     .parse::<f32>()
                     .ok()
-                    .map(|degrees| degrees / 100.0)
+                    .map(|percent| percent / 100.0)
             })),
+            bright_min: FloatParam::new(
+                "Min Brightness",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_unit("%")
+            .with_value_to_string(Arc::new(|value| {
+                format!("{:.0}", value * 100.0)
+            }))
+            .with_string_to_value(Arc::new(|string| {
+                string.trim_end_matches("%")
+                    .parse::<f32>()
+                    .ok()
+                    .map(|percent| percent / 100.0)
+            })),
+            bright_max: FloatParam::new(
+                "Max Brightness",
+                1.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_unit("%")
+            .with_value_to_string(Arc::new(|value| {
+                format!("{:.0}", value * 100.0)
+            }))
+            .with_string_to_value(Arc::new(|string| {
+                string.trim_end_matches("%")
+                    .parse::<f32>()
+                    .ok()
+                    .map(|percent| percent / 100.0)
+            })),
+            invert_brightness: BoolParam::new("Invert Brightness", false),
             immediate: BoolParam::new("Immediate", true),
+            // 0 keeps the old behavior of leaving transitions to the Immediate param
+            transition: FloatParam::new(
+                "Transition",
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(Arc::new(|value| {
+                format!("{:.0}", value)
+            }))
+            .with_string_to_value(Arc::new(|string| {
+                string.trim_end_matches(" ms")
+                    .parse::<f32>()
+                    .ok()
+            })),
+            power: BoolParam::new("Power", true),
+            white_mode: BoolParam::new("White Mode", false),
+            temperature: FloatParam::new(
+                "Temperature",
+                4000.0,
+                FloatRange::Linear {
+                    min: 2700.0,
+                    max: 6500.0,
+                },
+            )
+            .with_unit(" K")
+            .with_value_to_string(Arc::new(|value| {
+                format!("{:.0}", value)
+            }))
+            .with_string_to_value(Arc::new(|string| {
+                string.trim_end_matches(" K")
+                    .parse::<f32>()
+                    .ok()
+            })),
+            audio_reactive: BoolParam::new("Audio Reactive", false),
+            attack: FloatParam::new(
+                "Attack",
+                10.0,
+                FloatRange::Skewed {
+                    min: 1.0,
+                    max: 500.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(Arc::new(|value| {
+                format!("{:.0}", value)
+            }))
+            .with_string_to_value(Arc::new(|string| {
+                string.trim_end_matches(" ms")
+                    .parse::<f32>()
+                    .ok()
+            })),
+            release: FloatParam::new(
+                "Release",
+                200.0,
+                FloatRange::Skewed {
+                    min: 10.0,
+                    max: 2000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" ms")
+            .with_value_to_string(Arc::new(|value| {
+                format!("{:.0}", value)
+            }))
+            .with_string_to_value(Arc::new(|string| {
+                string.trim_end_matches(" ms")
+                    .parse::<f32>()
+                    .ok()
+            })),
+            hue_offset: FloatParam::new(
+                "Hue Offset",
+                0.0,
+                FloatRange::Linear {
+                    min: -180.0,
+                    max: 180.0,
+                },
+            )
+            .with_unit(" °")
+            .with_value_to_string(Arc::new(|value| {
+                format!("{:.0}", value)
+            }))
+            .with_string_to_value(Arc::new(|string| {
+                string.trim_end_matches(" °")
+                    .parse::<f32>()
+                    .ok()
+            })),
+            beat_sync: BoolParam::new("Beat Sync", false),
+            cycle_bars: FloatParam::new(
+                "Cycle Length",
+                4.0,
+                FloatRange::Skewed {
+                    min: 0.25,
+                    max: 64.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_unit(" bars")
+            .with_value_to_string(Arc::new(|value| {
+                format!("{:.2}", value)
+            }))
+            .with_string_to_value(Arc::new(|string| {
+                string.trim_end_matches(" bars")
+                    .parse::<f32>()
+                    .ok()
+            })),
+            midi_control: BoolParam::new("MIDI Control", false),
+            midi_channel: IntParam::new(
+                "MIDI Channel",
+                0,
+                IntRange::Linear {
+                    min: 0,
+                    max: 16,
+                },
+            )
+            .with_value_to_string(Arc::new(|value| {
+                if value == 0 {
+                    "Omni".to_string()
+                } else {
+                    value.to_string()
+                }
+            })),
+            recall: IntParam::new(
+                "Recall",
+                0,
+                IntRange::Linear {
+                    min: 0,
+                    max: MAX_PRESETS as i32,
+                },
+            )
+            .with_value_to_string(Arc::new(|value| {
+                if value == 0 {
+                    "None".to_string()
+                } else {
+                    value.to_string()
+                }
+            })),
+            idle_timeout: IntParam::new(
+                "Idle Timeout",
+                0,
+                IntRange::Linear {
+                    min: 0,
+                    max: 240,
+                },
+            )
+            .with_value_to_string(Arc::new(|value| {
+                if value == 0 {
+                    "Off".to_string()
+                } else {
+                    format!("{} min", value)
+                }
+            })),
+            two_bulbs: BoolParam::new("Two Bulbs", false),
+            right_hue: FloatParam::new(
+                "Right Hue",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_unit(" °")
+            .with_value_to_string(Arc::new(|value| {
+                format!("{:.0}", value * 360.0)
+            }))
+            .with_string_to_value(Arc::new(|string| {
+                string.trim_end_matches(" °")
+                    .parse::<f32>()
+                    .ok()
+                    .map(|degrees| degrees / 360.0)
+            })),
+            right_saturation: FloatParam::new(
+                "Right Saturation",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_unit("%")
+            .with_value_to_string(Arc::new(|value| {
+                format!("{:.0}", value * 100.0)
+            }))
+            .with_string_to_value(Arc::new(|string| {
+                string.trim_end_matches("%")
+                    .parse::<f32>()
+                    .ok()
+                    .map(|percent| percent / 100.0)
+            })),
+            right_brightness: FloatParam::new(
+                "Right Brightness",
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 1.0,
+                },
+            )
+            .with_unit("%")
+            .with_value_to_string(Arc::new(|value| {
+                format!("{:.0}", value * 100.0)
+            }))
+            .with_string_to_value(Arc::new(|string| {
+                string.trim_end_matches("%")
+                    .parse::<f32>()
+                    .ok()
+                    .map(|percent| percent / 100.0)
+            })),
+            presets: RwLock::new(Vec::new()),
+            hue_cc: AtomicU8::new(NO_CC),
+            saturation_cc: AtomicU8::new(NO_CC),
+            brightness_cc: AtomicU8::new(NO_CC),
+            learning: AtomicU8::new(0),
+            connection: RwLock::new(ConnectionSettings::default()),
+            right_connection: RwLock::new(ConnectionSettings::default()),
+            editor_state: editor::default_state(),
+        }
+    }
+}
+
+impl BulbVstParams {
+    fn cc_binding(&self, target: ColorTarget) -> &AtomicU8 {
+        match target {
+            ColorTarget::Hue => &self.hue_cc,
+            ColorTarget::Saturation => &self.saturation_cc,
+            ColorTarget::Brightness => &self.brightness_cc,
+        }
+    }
+
+    fn learning(&self) -> Option<ColorTarget> {
+        match self.learning.load(Ordering::Relaxed) {
+            0 => None,
+            index => ColorTarget::ALL.get(index as usize - 1).copied(),
+        }
+    }
+
+    /// The color the params are set to, in the ranges the bulb takes, see `to_bulb_range`
+    fn color(&self) -> ColorPreset {
+        ColorPreset {
+            hue: to_bulb_range(self.hue.value(), 360),
+            saturation: to_bulb_range(self.saturation.value(), 1000),
+            brightness: to_bulb_range(self.brightness.value(), 1000),
+        }
+    }
+
+    /// Like `color`, for the right bulb
+    fn right_color(&self) -> ColorPreset {
+        ColorPreset {
+            hue: to_bulb_range(self.right_hue.value(), 360),
+            saturation: to_bulb_range(self.right_saturation.value(), 1000),
+            brightness: to_bulb_range(self.right_brightness.value(), 1000),
+        }
+    }
+
+    /// Store a preset in the first empty slot, returns its Recall number or
+    /// `None` if all `MAX_PRESETS` are taken
+    fn save_preset(&self, name: String, color: ColorPreset) -> Option<usize> {
+        let mut presets = self.presets.write().unwrap_or_else(PoisonError::into_inner);
+        let index = presets.iter().position(Option::is_none).unwrap_or(presets.len());
+        if index >= MAX_PRESETS {
+            return None;
+        }
+
+        if index == presets.len() {
+            presets.push(None);
+        }
+        presets[index] = Some((name, color));
+        Some(index + 1)
+    }
+
+    /// Empty a preset's slot, the others keep their Recall number
+    fn delete_preset(&self, index: usize) {
+        let mut presets = self.presets.write().unwrap_or_else(PoisonError::into_inner);
+        if let Some(preset) = presets.get_mut(index) {
+            *preset = None;
+        }
+        while presets.last().is_some_and(Option::is_none) {
+            presets.pop();
         }
     }
 }
@@ -117,83 +694,733 @@ impl Plugin for BulbVst {
     const EMAIL: &'static str = "";
     const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
-    // we arent processing any audio, just midi signals
-    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[AudioIOLayout {
-        main_input_channels: None,
-        main_output_channels: None,
-        aux_input_ports: &[],
-        aux_output_ports: &[],
-        names: PortNames::const_default(),
-    }];
+    // audio passes through untouched, it's only listened to in Audio Reactive mode.
+    // hosts that put us on a MIDI track can still use the layout without audio
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(2),
+            main_output_channels: NonZeroU32::new(2),
+            aux_input_ports: &[],
+            aux_output_ports: &[],
+            names: PortNames::const_default(),
+        },
+        AudioIOLayout {
+            main_input_channels: None,
+            main_output_channels: None,
+            aux_input_ports: &[],
+            aux_output_ports: &[],
+            names: PortNames::const_default(),
+        },
+    ];
 
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
-    type SysExMessage = ();
+    type SysExMessage = BulbSysEx;
     type BackgroundTask = ();
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
+    fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
+        editor::create(
+            self.params.clone(),
+            [self.status.clone(), self.right_status.clone()],
+            self.command_tx.clone(),
+        )
+    }
+
+    fn initialize(
+        &mut self,
+        _audio_io_layout: &AudioIOLayout,
+        buffer_config: &BufferConfig,
+        _context: &mut impl InitContext<Self>,
+    ) -> bool {
+        self.sample_rate = buffer_config.sample_rate;
+        self.envelope = 0.0;
+
+        // the saved connection settings are only available once the host has restored our state
+        let connection = self.params.connection.read().unwrap_or_else(PoisonError::into_inner).clone();
+        if self.last_connection.as_ref() != Some(&connection) {
+            self.command_tx.send((Side::Left, BulbCommand::Configure(connection.clone()))).ok();
+            self.last_connection = Some(connection);
+        }
+        let right_connection = self.params.right_connection.read().unwrap_or_else(PoisonError::into_inner).clone();
+        if !right_connection.device_id.is_empty() && self.last_right_connection.as_ref() != Some(&right_connection) {
+            self.command_tx.send((Side::Right, BulbCommand::Configure(right_connection.clone()))).ok();
+            self.last_right_connection = Some(right_connection);
+        }
+
+        true
+    }
+
     fn process(
         &mut self,
-        _buffer: &mut Buffer,
+        buffer: &mut Buffer,
         _aux: &mut AuxiliaryBuffers,
-        _context: &mut impl ProcessContext<Self>,
+        context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
-        let hue = (self.params.hue.value() * 360.0) as u16;
-        let saturation = (self.params.saturation.value() * 1000.0) as u16;
-        let brightness = (self.params.brightness.value() * 1000.0) as u16;
-        let immediate = self.params.immediate.value();
+        self.follow_level(buffer);
 
-        if hue != self.last_hue || saturation != self.last_saturation || brightness != self.last_brightness {
+        let channel = self.params.midi_channel.value();
+        while let Some(event) = context.next_event() {
+            // SysEx has no channel, so it always gets through
+            if channel > 0 && event.channel().is_some_and(|event_channel| event_channel != channel as u8 - 1) {
+                continue;
+            }
+
+            match event {
+                NoteEvent::NoteOn { note, velocity, .. } => {
+                    self.note_color = Some((note_to_hue(note), to_bulb_range(velocity, 1000)));
+                }
+                NoteEvent::MidiCC { cc, value, .. } => self.handle_cc(cc, value),
+                NoteEvent::MidiSysEx { message, .. } => self.handle_sysex(message),
+                _ => {}
+            }
+        }
+
+        // events and the level are still followed, so re-enabling picks up where things are now
+        let enabled = self.params.enabled.value();
+        if enabled != self.last_enabled {
+            self.last_enabled = enabled;
+            if enabled {
+                self.last_power = None;
+                self.last_white_mode = None;
+                self.force_resend();
+            }
+        }
+        if !enabled {
+            return ProcessStatus::Normal;
+        }
+
+        let ColorPreset { mut hue, mut saturation, mut brightness } = self.params.color();
+        if self.params.midi_control.value() {
+            if let Some((note_hue, note_brightness)) = self.note_color {
+                hue = note_hue;
+                brightness = note_brightness;
+            }
+        }
+        for target in ColorTarget::ALL {
+            if self.params.cc_binding(target).load(Ordering::Relaxed) == NO_CC {
+                continue;
+            }
+            match (target, self.cc_values[target as usize]) {
+                (ColorTarget::Hue, Some(value)) => hue = to_bulb_range(value, 360),
+                (ColorTarget::Saturation, Some(value)) => saturation = to_bulb_range(value, 1000),
+                (ColorTarget::Brightness, Some(value)) => brightness = to_bulb_range(value, 1000),
+                (_, None) => {}
+            }
+        }
+        if let Some((color, params_then)) = self.sysex_color {
+            if params_then == self.params.color() {
+                ColorPreset { hue, saturation, brightness } = color;
+            } else {
+                self.sysex_color = None;
+            }
+        }
+        if self.params.audio_reactive.value() {
+            brightness = level_to_brightness(self.envelope);
+        }
+        let phase = if self.params.beat_sync.value() {
+            self.cycle_phase(context.transport())
+        } else {
+            None
+        };
+        let brightness = self.map_brightness(brightness);
+        let hue = self.rotate_hue(hue, phase);
+        let recall = self.params.recall.value();
+        let power = self.params.power.value();
+        let white_mode = self.params.white_mode.value();
+        let temperature = kelvin_to_temperature(self.params.temperature.value().round() as u16);
+        let two_bulbs = self.params.two_bulbs.value();
+
+        // resent when Two Bulbs is turned on too, the right bulb may still be off
+        if self.last_power != Some((power, two_bulbs)) {
+            self.last_power = Some((power, two_bulbs));
+
+            // not short circuiting, so both are tried and both retried if either fails
+            let sent = self.send(Side::Left, BulbCommand::SetPower(power))
+                & (!two_bulbs || self.send(Side::Right, BulbCommand::SetPower(power)));
+            if !sent {
+                self.last_power = None;
+            }
+            if power {
+                // colors aren't sent while off, so catch up with the current one
+                self.force_resend();
+            }
+        }
+
+        if self.last_white_mode != Some(white_mode) {
+            self.last_white_mode = Some(white_mode);
+            self.force_resend();
+        }
+
+        // nothing to show while off, the color is caught up on when it's turned back on
+        if !power {
+            return ProcessStatus::Normal;
+        }
+
+        if self.last_send.is_some_and(|sent| sent.elapsed() < MIN_SEND_INTERVAL) {
+            return ProcessStatus::Normal;
+        }
+
+        if two_bulbs {
+            self.update_right(white_mode, temperature, phase);
+        } else {
+            // so turning Two Bulbs back on resends
+            self.last_right_color = None;
+            self.last_right_white = None;
+        }
+
+        if recall != self.last_recall {
+            // the editor only holds this lock briefly while saving, so try again next time
+            let Ok(presets) = self.params.presets.try_read() else {
+                return ProcessStatus::Normal;
+            };
+            let preset = usize::try_from(recall - 1)
+                .ok()
+                .and_then(|index| presets.get(index)?.as_ref())
+                .map(|(_, preset)| *preset);
+            drop(presets);
+
+            match preset {
+                Some(preset) => {
+                    // the params keep their values, so moving one afterwards takes over again
+                    if self.send_color(preset.hue, preset.saturation, preset.brightness) {
+                        self.last_recall = recall;
+                    }
+                    return ProcessStatus::Normal;
+                }
+                None => self.last_recall = recall,
+            }
+        }
+
+        if white_mode {
+            if (temperature != self.last_temperature || brightness != self.last_brightness)
+                && self.send(Side::Left, BulbCommand::SetWhite(temperature, brightness))
+            {
+                self.last_temperature = temperature;
+                self.last_brightness = brightness;
+            }
+        } else if hue != self.last_hue || saturation != self.last_saturation || brightness != self.last_brightness {
+            self.send_color(hue, saturation, brightness);
+        }
+
+        ProcessStatus::Normal
+    }
+}
+
+impl BulbVst {
+    /// Queue a command for the bulb thread without blocking
+    ///
+    /// Returns false if the channel is full. Callers then leave their last sent
+    /// value alone, so the newest state is retried on the next process call
+    /// instead of queueing behind stale commands.
+    fn send(&mut self, side: Side, command: BulbCommand) -> bool {
+        match self.command_tx.try_send((side, command)) {
+            Ok(()) => {
+                self.last_send = Some(Instant::now());
+                true
+            }
+            Err(_) => {
+                self.dropped_commands.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Update the envelope with the RMS of this block, rising at the attack rate and falling at the release rate
+    fn follow_level(&mut self, buffer: &Buffer) {
+        let samples = buffer.samples();
+        let channels = buffer.as_slice_immutable();
+        if samples == 0 || channels.is_empty() {
+            return;
+        }
+
+        let sum: f32 = channels.iter().flat_map(|channel| channel.iter()).map(|sample| sample * sample).sum();
+        let rms = (sum / (samples * channels.len()) as f32).sqrt();
+
+        let time_ms = if rms > self.envelope {
+            self.params.attack.value()
+        } else {
+            self.params.release.value()
+        };
+        let block_ms = samples as f32 / self.sample_rate * 1000.0;
+        let coefficient = (-block_ms / time_ms).exp();
+        self.envelope = rms + (self.envelope - rms) * coefficient;
+    }
+
+    /// How far through the current Beat Sync cycle the host is, 0-1
+    ///
+    /// None while the host isn't playing or doesn't report its position, the
+    /// hue then stays where the params put it.
+    fn cycle_phase(&self, transport: &Transport) -> Option<f32> {
+        if !transport.playing {
+            return None;
+        }
+
+        let beats_per_bar = transport.time_sig_numerator.filter(|beats| *beats > 0).unwrap_or(4) as f64;
+        let cycle_beats = self.params.cycle_bars.value() as f64 * beats_per_bar;
+        Some((transport.pos_beats()? / cycle_beats).rem_euclid(1.0) as f32)
+    }
+
+    /// Apply the Beat Sync phase and the Hue Offset to a 0-360 hue
+    fn rotate_hue(&self, hue: u16, phase: Option<f32>) -> u16 {
+        let beat = phase.map_or(0, |phase| (phase * 360.0).round() as i32);
+        // wrapping also folds 360 into 0, so the same color never goes out twice
+        (hue as i32 + beat + self.params.hue_offset.value().round() as i32).rem_euclid(360) as u16
+    }
+
+    /// Send the Right params to the right bulb if they changed
+    ///
+    /// The right bulb follows its own color params with the same brightness
+    /// range, hue offset and beat sync. Notes, CCs, Audio Reactive and presets
+    /// only drive the left bulb.
+    fn update_right(&mut self, white_mode: bool, temperature: u16, phase: Option<f32>) {
+        let color = self.params.right_color();
+        let brightness = self.map_brightness(color.brightness);
+
+        if white_mode {
+            let white = (temperature, brightness);
+            if self.last_right_white != Some(white) && self.send(Side::Right, BulbCommand::SetWhite(temperature, brightness)) {
+                self.last_right_white = Some(white);
+                self.last_right_color = None;
+            }
+        } else {
+            let color = ColorPreset {
+                hue: self.rotate_hue(color.hue, phase),
+                saturation: color.saturation,
+                brightness,
+            };
+            let command = self.color_command(color.hue, color.saturation, color.brightness);
+            if self.last_right_color != Some(color) && self.send(Side::Right, command) {
+                self.last_right_color = Some(color);
+                self.last_right_white = None;
+            }
+        }
+    }
+
+    /// Remap a 0-1000 brightness onto the Min/Max Brightness range, inverting it first if enabled
+    fn map_brightness(&self, brightness: u16) -> u16 {
+        let mut value = brightness as f32 / 1000.0;
+        if self.params.invert_brightness.value() {
+            value = 1.0 - value;
+        }
+
+        let min = self.params.bright_min.value();
+        let max = self.params.bright_max.value().max(min);
+        to_bulb_range(min + value * (max - min), 1000)
+    }
+
+    /// Hold the color from a SysEx message until one of the color params moves
+    fn handle_sysex(&mut self, message: BulbSysEx) {
+        let color = match message {
+            BulbSysEx::Color { hue, saturation, brightness } => ColorPreset { hue, saturation, brightness },
+            BulbSysEx::Recall(index) => {
+                // the editor only holds this lock briefly while saving, a recall arriving right then is lost
+                let Ok(presets) = self.params.presets.try_read() else {
+                    return;
+                };
+                let Some(Some((_, preset))) = presets.get(index as usize - 1) else {
+                    return;
+                };
+                *preset
+            }
+        };
+        self.sysex_color = Some((color, self.params.color()));
+    }
+
+    /// Record a CC value for the params bound to it, binding it first if the editor is learning
+    fn handle_cc(&mut self, cc: u8, value: f32) {
+        if let Some(target) = self.params.learning() {
+            self.params.cc_binding(target).store(cc, Ordering::Relaxed);
+            self.params.learning.store(0, Ordering::Relaxed);
+        }
+
+        for target in ColorTarget::ALL {
+            if self.params.cc_binding(target).load(Ordering::Relaxed) == cc {
+                self.cc_values[target as usize] = Some(value);
+            }
+        }
+    }
+
+    /// A command setting a color, fading to it if a transition time is set
+    fn color_command(&self, hue: u16, saturation: u16, brightness: u16) -> BulbCommand {
+        let transition = self.params.transition.value();
+        if transition >= 1.0 {
+            BulbCommand::FadeHSV(hue, saturation, brightness, Duration::from_millis(transition as u64))
+        } else {
+            BulbCommand::SetHSV(hue, saturation, brightness, self.params.immediate.value())
+        }
+    }
+
+    /// Send a color to the left bulb, fading to it if a transition time is set
+    fn send_color(&mut self, hue: u16, saturation: u16, brightness: u16) -> bool {
+        let command = self.color_command(hue, saturation, brightness);
+        let sent = self.send(Side::Left, command);
+        if sent {
             self.last_hue = hue;
             self.last_saturation = saturation;
             self.last_brightness = brightness;
-            self.command_tx.send(BulbCommand::SetHSV(hue, saturation, brightness, immediate)).ok();
         }
+        sent
+    }
 
-        ProcessStatus::Normal
+    /// Forget the last sent values so the current state is sent on the next process call
+    fn force_resend(&mut self) {
+        self.last_hue = u16::MAX;
+        self.last_saturation = u16::MAX;
+        self.last_brightness = u16::MAX;
+        self.last_temperature = u16::MAX;
+        self.last_right_color = None;
+        self.last_right_white = None;
     }
 }
 
-fn bulb_controller_thread(command_rx: Receiver<BulbCommand>) {
-    let rt = tokio::runtime::Runtime::new().unwrap();
+/// Scale a normalized param value (0-1) onto one of the bulb's ranges (0-`max`)
+///
+/// Values are rounded rather than truncated, so the end of an automation
+/// ramp lands on the value it was automated to, e.g. 0.5999 is 600 not 599.
+fn to_bulb_range(value: f32, max: u16) -> u16 {
+    (value.clamp(0.0, 1.0) * max as f32).round() as u16
+}
+
+/// Map an RMS level onto 0-1000 brightness, with -60 dB and below being dark
+fn level_to_brightness(level: f32) -> u16 {
+    let db = 20.0 * level.max(1e-6).log10();
+    (((db + 60.0) / 60.0).clamp(0.0, 1.0) * 1000.0).round() as u16
+}
+
+fn bulb_controller_thread(
+    params: Arc<BulbVstParams>,
+    command_rx: Receiver<(Side, BulbCommand)>,
+    dropped_commands: Arc<AtomicU32>,
+    statuses: [Arc<Mutex<ConnectionStatus>>; 2],
+) {
+    let rt = match tokio::runtime::Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            // later commands fail to queue, which `send` already copes with
+            nih_error!("Failed to start the bulb thread: {}", e);
+            for status in &statuses {
+                ConnectionStatus::update(status, ConnectionState::Unconfigured, Some(format!("Bulb thread failed to start: {}", e)));
+            }
+            return;
+        }
+    };
 
     rt.block_on(async {
-        let mut controller = BulbController::new(BulbConfig::new(
-            "eb052a1de220394996xwke",
-            "!BY}~:dab1nuT;'n",
-            "192.168.0.124",
-            "3.3",
-        )).unwrap();
-
-        if controller.connect().await.is_ok() {
-            nih_log!("Bulb connected successfully");
-        } else {
-            nih_error!("Failed to connect to bulb");
-        }
-
-        while let Ok(command) = command_rx.recv() {
-            match command {
-                BulbCommand::SetHSV(hue, saturation, brightness, immediate) => {
-                    match controller.set_color(hue, saturation, brightness, immediate).await {
-                        Ok(_) => {
-                            nih_log!(
-                                "Set bulb color to H:{} S:{} B:{}",
-                                hue,
-                                saturation,
-                                brightness
-                            );
-                        }
-                        Err(e) => {
-                            nih_error!("Failed to set bulb color: {}", e);
+        // nothing to talk to until the host has restored our state and we've been configured
+        let mut bulbs: [Option<Bulb>; 2] = [None, None];
+        // commands for the other bulb found while skipping ahead, handled in order next
+        let mut deferred = VecDeque::new();
+        // any command counts as activity, `process` only sends when something changed
+        let mut last_activity = Instant::now();
+        let mut idle = false;
+
+        'commands: loop {
+            let next = match deferred.pop_front() {
+                Some(next) => Some(next),
+                None => loop {
+                    // the param is read again on every wakeup, so changing it mid-wait takes effect
+                    let minutes = params.idle_timeout.value();
+                    let wait = if minutes > 0 && !idle {
+                        let remaining = Duration::from_secs(minutes as u64 * 60).saturating_sub(last_activity.elapsed());
+                        if remaining.is_zero() {
+                            break None;
                         }
+                        remaining.min(IDLE_CHECK_INTERVAL)
+                    } else {
+                        IDLE_CHECK_INTERVAL
+                    };
+
+                    match command_rx.recv_timeout(wait) {
+                        Ok(next) => break Some(next),
+                        Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break 'commands,
+                    }
+                },
+            };
+
+            let Some((side, mut command)) = next else {
+                nih_log!(
+                    "No changes for {} minutes, turning the bulbs off",
+                    params.idle_timeout.value()
+                );
+                for bulb in in_use(&mut bulbs, &params).flatten() {
+                    bulb.run(BulbCommand::SetPower(false)).await;
+                }
+                idle = true;
+                continue;
+            };
+
+            last_activity = Instant::now();
+            if std::mem::take(&mut idle) && !matches!(command, BulbCommand::SetPower(false) | BulbCommand::Shutdown) {
+                // colors leave the power alone, so turn every bulb back on first
+                for bulb in in_use(&mut bulbs, &params).flatten() {
+                    bulb.run(BulbCommand::SetPower(true)).await;
+                }
+            }
+
+            // a fade holds the thread for a while, so skip to the newest command for this bulb if more are queued
+            while matches!(command, BulbCommand::FadeHSV(..)) {
+                match command_rx.try_recv() {
+                    Ok((next_side, next)) if next_side == side => command = next,
+                    Ok(other) => deferred.push_back(other),
+                    Err(_) => break,
+                }
+            }
+
+            let dropped = dropped_commands.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                nih_log!("Command channel was full, dropped {} commands", dropped);
+            }
+
+            let status = &statuses[side as usize];
+            let slot = &mut bulbs[side as usize];
+            let bulb = match command {
+                BulbCommand::Shutdown => break,
+                BulbCommand::Configure(settings) => {
+                    if let Some(old) = slot.take() {
+                        old.close().await;
+                    }
+                    // the right bulb is only used once it's been set up, it has no fallback
+                    *slot = if side == Side::Right && settings.device_id.is_empty() {
+                        ConnectionStatus::update(status, ConnectionState::Unconfigured, None);
+                        None
+                    } else {
+                        connect_bulb(&settings, status).await
+                    };
+                    continue;
+                }
+                _ => match slot.as_mut() {
+                    Some(bulb) => bulb,
+                    None => {
+                        nih_log!("No {:?} bulb configured, ignoring command", side);
+                        continue;
                     }
+                },
+            };
+
+            let error = bulb.run(command).await;
+            let state = if bulb.is_connected() {
+                ConnectionState::Connected
+            } else {
+                ConnectionState::Disconnected
+            };
+            ConnectionStatus::update(status, state, error);
+        }
+
+        // release the sockets before the runtime goes away
+        for bulb in bulbs.into_iter().flatten() {
+            bulb.close().await;
+        }
+    });
+}
+
+/// The bulbs being driven, the right one only with Two Bulbs on
+fn in_use<'a>(bulbs: &'a mut [Option<Bulb>; 2], params: &BulbVstParams) -> impl Iterator<Item = &'a mut Option<Bulb>> {
+    let count = if params.two_bulbs.value() { 2 } else { 1 };
+    bulbs.iter_mut().take(count)
+}
+
+/// A bulb as the bulb thread sees it, either its own connection or one
+/// shared with other instances
+enum Bulb {
+    Own(BulbController),
+    Shared(Arc<SharedBulb>),
+}
+
+impl Bulb {
+    fn config(&self) -> &BulbConfig {
+        match self {
+            Bulb::Own(controller) => controller.config(),
+            Bulb::Shared(shared) => shared.config(),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        match self {
+            Bulb::Own(controller) => controller.is_connected(),
+            Bulb::Shared(shared) => shared.is_connected(),
+        }
+    }
+
+    /// Returns the error message if connecting failed
+    async fn connect(&mut self) -> Option<String> {
+        match self {
+            Bulb::Own(controller) => controller.connect().await.err().map(|e| e.to_string()),
+            Bulb::Shared(shared) => shared.connect().await,
+        }
+    }
+
+    /// Returns the error message if the command failed
+    async fn run(&mut self, command: BulbCommand) -> Option<String> {
+        match self {
+            Bulb::Own(controller) => run_command(controller, command).await,
+            Bulb::Shared(shared) => shared.run(command).await,
+        }
+    }
+
+    /// Disconnect, a shared bulb only once no other instance uses it
+    async fn close(self) {
+        match self {
+            Bulb::Own(mut controller) => {
+                if let Err(e) = controller.disconnect().await {
+                    nih_error!("Failed to disconnect from bulb: {}", e);
+                }
+            }
+            Bulb::Shared(shared) => drop(shared),
+        }
+    }
+}
+
+/// Send a color, white or power command and log how it went, returns the
+/// error message if it failed
+async fn run_command(bulb: &mut BulbController, command: BulbCommand) -> Option<String> {
+    let mut error = None;
+    match command {
+        BulbCommand::Shutdown | BulbCommand::Configure(_) => {
+            // handled by the bulb thread itself, never passed on
+            nih_error!("Ignoring a thread command sent to the bulb");
+            error = Some("Not a bulb command".to_string());
+        }
+        BulbCommand::FadeHSV(hue, saturation, brightness, duration) => {
+            match bulb.fade_to(hue, saturation, brightness, duration).await {
+                Ok(_) => {
+                    nih_log!(
+                        "Faded bulb color to H:{} S:{} B:{} over {:?}",
+                        hue,
+                        saturation,
+                        brightness,
+                        duration
+                    );
+                }
+                Err(e) => {
+                    nih_error!("Failed to fade bulb color: {}", e);
+                    error = Some(e.to_string());
+                }
+            }
+        }
+        BulbCommand::SetWhite(temperature, brightness) => {
+            match bulb.set_white(brightness, temperature).await {
+                Ok(_) => {
+                    nih_log!("Set bulb white to T:{} B:{}", temperature, brightness);
+                }
+                Err(e) => {
+                    nih_error!("Failed to set bulb white: {}", e);
+                    error = Some(e.to_string());
+                }
+            }
+        }
+        BulbCommand::SetPower(on) => {
+            match bulb.set_power(on).await {
+                Ok(_) => {
+                    nih_log!("Set bulb power to {}", if on { "on" } else { "off" });
+                }
+                Err(e) => {
+                    nih_error!("Failed to set bulb power: {}", e);
+                    error = Some(e.to_string());
+                }
+            }
+        }
+        BulbCommand::SetHSV(hue, saturation, brightness, immediate) => {
+            match bulb.set_color(hue, saturation, brightness, immediate).await {
+                Ok(_) => {
+                    nih_log!(
+                        "Set bulb color to H:{} S:{} B:{}",
+                        hue,
+                        saturation,
+                        brightness
+                    );
+                }
+                Err(e) => {
+                    nih_error!("Failed to set bulb color: {}", e);
+                    error = Some(e.to_string());
                 }
             }
         }
+    }
+    error
+}
+
+/// Create a bulb for the given settings and connect to it
+///
+/// Falls back to the `BULB_*` environment variables if no bulb has been set up
+/// in the plugin state. A failed connect still returns the bulb, since
+/// commands reconnect on their own.
+async fn connect_bulb(settings: &ConnectionSettings, status: &Mutex<ConnectionStatus>) -> Option<Bulb> {
+    let config = if settings.device_id.is_empty() {
+        BulbConfig::from_env()
+    } else {
+        BulbConfig::new(&settings.device_id, &settings.local_key, &settings.ip, &settings.version)
+    };
+    let bulb = config.and_then(|config| {
+        if settings.share {
+            SharedBulb::acquire(config).map(Bulb::Shared)
+        } else {
+            BulbController::new(config).map(Bulb::Own)
+        }
     });
+
+    let mut bulb = match bulb {
+        Ok(bulb) => bulb,
+        Err(e) => {
+            nih_error!("Invalid bulb config: {}", e);
+            ConnectionStatus::update(status, ConnectionState::Unconfigured, Some(e.to_string()));
+            return None;
+        }
+    };
+
+    // never log the local key
+    nih_log!("Connecting to bulb {} at {}", bulb.config().device_id, bulb.config().ip);
+    ConnectionStatus::update(status, ConnectionState::Connecting, None);
+    match bulb.connect().await {
+        None => {
+            nih_log!("Bulb connected successfully");
+            ConnectionStatus::update(status, ConnectionState::Connected, None);
+        }
+        Some(error) => {
+            nih_error!("Failed to connect to bulb: {}", error);
+            ConnectionStatus::update(status, ConnectionState::Disconnected, Some(error));
+        }
+    }
+
+    Some(bulb)
+}
+
+impl Drop for BulbVst {
+    fn drop(&mut self) {
+        self.command_tx.send_timeout((Side::Left, BulbCommand::Shutdown), SHUTDOWN_TIMEOUT).ok();
+
+        let Some(bulb_thread) = self.bulb_thread.take() else {
+            return;
+        };
+
+        // don't let a hung reconnect wedge the host, detach the thread instead
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        while !bulb_thread.is_finished() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        if bulb_thread.is_finished() {
+            bulb_thread.join().ok();
+        } else {
+            nih_error!("Bulb thread didn't shut down in time, detaching it");
+        }
+    }
+}
+
+impl ClapPlugin for BulbVst {
+    const CLAP_ID: &'static str = "com.micah.bulb-controller";
+    const CLAP_DESCRIPTION: Option<&'static str> = Some("Controls a Tuya smart bulb from automation");
+    const CLAP_MANUAL_URL: Option<&'static str> = None;
+    const CLAP_SUPPORT_URL: Option<&'static str> = None;
+    const CLAP_FEATURES: &'static [ClapFeature] = &[
+        ClapFeature::AudioEffect,
+        ClapFeature::Utility,
+    ];
 }
 
 impl Vst3Plugin for BulbVst {
@@ -204,4 +1431,120 @@ impl Vst3Plugin for BulbVst {
     ];
 }
 
+nih_export_clap!(BulbVst);
 nih_export_vst3!(BulbVst);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_bulb_range_rounds() {
+        assert_eq!(to_bulb_range(0.0, 1000), 0);
+        assert_eq!(to_bulb_range(0.5999, 1000), 600);
+        assert_eq!(to_bulb_range(0.0004, 1000), 0);
+        assert_eq!(to_bulb_range(1.0, 1000), 1000);
+        assert_eq!(to_bulb_range(1.0, 360), 360);
+    }
+
+    #[test]
+    fn automation_ramp_lands_on_its_target() {
+        // hosts step automation in f32, so the last value is a hair off the target
+        let (from, to, steps) = (0.2f32, 0.6f32, 97);
+        let mut value = from;
+        let mut previous = to_bulb_range(value, 1000);
+
+        for step in 1..=steps {
+            value += (to - from) / steps as f32;
+            let scaled = to_bulb_range(value, 1000);
+
+            let exact = (from + (to - from) * step as f32 / steps as f32) * 1000.0;
+            assert!((scaled as f32 - exact).abs() <= 0.5 + 1e-3, "step {}: {} vs {}", step, scaled, exact);
+            assert!(scaled >= previous, "step {}: went back from {} to {}", step, previous, scaled);
+            previous = scaled;
+        }
+        assert_ne!(value, to);
+        assert_eq!(previous, 600);
+    }
+
+    #[test]
+    fn deleting_a_preset_keeps_the_recall_numbers() {
+        let params = BulbVstParams::default();
+        let color = |hue| ColorPreset { hue, saturation: 1000, brightness: 1000 };
+        for hue in 0..3 {
+            assert_eq!(params.save_preset(hue.to_string(), color(hue)), Some(hue as usize + 1));
+        }
+
+        params.delete_preset(0);
+        let names = |params: &BulbVstParams| {
+            let presets = params.presets.read().unwrap();
+            presets.iter().map(|preset| preset.as_ref().map(|(name, _)| name.clone())).collect::<Vec<_>>()
+        };
+        assert_eq!(names(&params), [None, Some("1".to_string()), Some("2".to_string())]);
+
+        // the gap is filled first
+        assert_eq!(params.save_preset("3".to_string(), color(3)), Some(1));
+        assert_eq!(params.presets.read().unwrap()[0], Some(("3".to_string(), color(3))));
+
+        // empty slots at the end go away
+        params.delete_preset(2);
+        params.delete_preset(1);
+        assert_eq!(names(&params), [Some("3".to_string())]);
+    }
+
+    #[test]
+    fn saving_stops_at_max_presets() {
+        let params = BulbVstParams::default();
+        let color = ColorPreset { hue: 0, saturation: 0, brightness: 0 };
+        for index in 0..MAX_PRESETS {
+            assert_eq!(params.save_preset(index.to_string(), color), Some(index + 1));
+        }
+        assert_eq!(params.save_preset("full".to_string(), color), None);
+
+        params.delete_preset(4);
+        assert_eq!(params.save_preset("fifth".to_string(), color), Some(5));
+        assert_eq!(params.presets.read().unwrap().len(), MAX_PRESETS);
+    }
+
+    #[test]
+    fn failing_command_keeps_the_bulb_thread_running() {
+        let params = Arc::new(BulbVstParams::default());
+        let statuses = [Arc::new(Mutex::new(ConnectionStatus::default())), Arc::new(Mutex::new(ConnectionStatus::default()))];
+        let (command_tx, command_rx) = bounded(16);
+        let thread = std::thread::spawn({
+            let (params, statuses) = (params.clone(), statuses.clone());
+            move || bulb_controller_thread(params, command_rx, Arc::new(AtomicU32::new(0)), statuses)
+        });
+
+        // nothing listens there, so connecting fails but the bulb is kept for retries
+        let settings = ConnectionSettings {
+            device_id: "bf0123456789abcdefgh".to_string(),
+            local_key: "0123456789abcdef".to_string(),
+            ip: "127.0.0.1".to_string(),
+            ..Default::default()
+        };
+        command_tx.send((Side::Left, BulbCommand::Configure(settings))).unwrap();
+
+        let wait_for_error = |text: &str| {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while !ConnectionStatus::get(&statuses[0]).last_error.is_some_and(|e| e.contains(text)) {
+                assert!(Instant::now() < deadline, "status is {:?}", ConnectionStatus::get(&statuses[0]));
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        // both are rejected before anything is sent
+        command_tx.send((Side::Left, BulbCommand::SetHSV(361, 1000, 1000, true))).unwrap();
+        wait_for_error("Hue 361");
+        assert!(!thread.is_finished());
+        command_tx
+            .send((Side::Left, BulbCommand::FadeHSV(0, 1001, 1000, Duration::from_secs(1))))
+            .unwrap();
+        wait_for_error("Saturation 1001");
+        assert!(!thread.is_finished());
+
+        command_tx.send((Side::Left, BulbCommand::Shutdown)).unwrap();
+        // a panic would come back from join as an error
+        assert!(thread.join().is_ok());
+    }
+}
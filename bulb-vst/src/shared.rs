@@ -0,0 +1,193 @@
+//! Connections shared between plugin instances
+//!
+//! Instances with Share turned on that point at the same device ID use one
+//! controller on a thread of its own instead of each connecting separately.
+//! Commands from every instance go through one queue, so the last one sent
+//! wins. The connection is closed once the last instance lets go of it.
+
+use crate::{run_command, BulbCommand};
+use bulb_core::{BulbConfig, BulbController};
+use nih_plug::prelude::*;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread::JoinHandle;
+use tokio::sync::{mpsc, oneshot};
+
+/// Every shared bulb that's still in use, by device ID
+static REGISTRY: Mutex<BTreeMap<String, Weak<SharedBulb>>> = Mutex::new(BTreeMap::new());
+
+/// Threads of released bulbs that may still be disconnecting, by device ID.
+/// Only locked while holding `REGISTRY`.
+static STOPPING: Mutex<BTreeMap<String, JoinHandle<()>>> = Mutex::new(BTreeMap::new());
+
+const STOPPED: &str = "Shared bulb thread has stopped";
+
+enum Job {
+    /// Connect unless already connected
+    Connect,
+    Command(BulbCommand),
+}
+
+/// The error message of a failed job, if any
+type Reply = oneshot::Sender<Option<String>>;
+
+pub(crate) struct SharedBulb {
+    config: BulbConfig,
+    jobs: Option<mpsc::UnboundedSender<(Job, Reply)>>,
+    connected: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SharedBulb {
+    /// The shared bulb for this device ID, starting it if no instance uses it yet
+    ///
+    /// The instance that starts it decides the rest of the settings, later
+    /// ones with different settings join it anyway.
+    pub(crate) fn acquire(config: BulbConfig) -> bulb_core::Result<Arc<Self>> {
+        let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+        registry.retain(|_, bulb| bulb.strong_count() > 0);
+
+        if let Some(bulb) = registry.get(&config.device_id).and_then(Weak::upgrade) {
+            if bulb.config.local_key != config.local_key || bulb.config.ip != config.ip {
+                nih_log!(
+                    "Bulb {} is already shared with different settings, using those",
+                    config.device_id
+                );
+            }
+            return Ok(bulb);
+        }
+
+        let controller = BulbController::new(config.clone())?;
+        let (jobs, jobs_rx) = mpsc::unbounded_channel();
+        let connected = Arc::new(AtomicBool::new(false));
+        // the bulb may not take a second connection, so let the last one close first
+        let previous = STOPPING.lock().unwrap_or_else(PoisonError::into_inner).remove(&config.device_id);
+        let thread = std::thread::spawn({
+            let connected = connected.clone();
+            move || {
+                if let Some(previous) = previous {
+                    previous.join().ok();
+                }
+                run(controller, jobs_rx, connected)
+            }
+        });
+
+        let bulb = Arc::new(Self {
+            config,
+            jobs: Some(jobs),
+            connected,
+            thread: Some(thread),
+        });
+        registry.insert(bulb.config.device_id.clone(), Arc::downgrade(&bulb));
+        Ok(bulb)
+    }
+
+    pub(crate) fn config(&self) -> &BulbConfig {
+        &self.config
+    }
+
+    /// As of the last job from any instance
+    pub(crate) fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Connect if no other instance has yet, returns the error if that fails
+    pub(crate) async fn connect(&self) -> Option<String> {
+        self.submit(Job::Connect).await
+    }
+
+    /// Run a bulb command on the shared thread, returns the error if it failed
+    pub(crate) async fn run(&self, command: BulbCommand) -> Option<String> {
+        self.submit(Job::Command(command)).await
+    }
+
+    async fn submit(&self, job: Job) -> Option<String> {
+        let (reply, reply_rx) = oneshot::channel();
+        let queued = self.jobs.as_ref().is_some_and(|jobs| jobs.send((job, reply)).is_ok());
+        if !queued {
+            return Some(STOPPED.to_string());
+        }
+        reply_rx.await.unwrap_or_else(|_| Some(STOPPED.to_string()))
+    }
+}
+
+impl Drop for SharedBulb {
+    fn drop(&mut self) {
+        // closing the queue ends the thread once it has disconnected. This
+        // can run on a bulb thread's runtime, so it's never waited for here
+        self.jobs.take();
+
+        let mut registry = REGISTRY.lock().unwrap_or_else(PoisonError::into_inner);
+        // an instance may already have started a new one for the device
+        if registry.get(&self.config.device_id).is_some_and(|bulb| bulb.strong_count() == 0) {
+            registry.remove(&self.config.device_id);
+        }
+
+        let mut stopping = STOPPING.lock().unwrap_or_else(PoisonError::into_inner);
+        stopping.retain(|_, thread| !thread.is_finished());
+        if let Some(thread) = self.thread.take() {
+            stopping.insert(self.config.device_id.clone(), thread);
+        }
+    }
+}
+
+fn run(mut controller: BulbController, mut jobs: mpsc::UnboundedReceiver<(Job, Reply)>, connected: Arc<AtomicBool>) {
+    // without a runtime the queue is dropped unanswered, so every job reports the thread as stopped
+    let rt = match tokio::runtime::Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => {
+            nih_error!("Failed to start the shared bulb thread: {}", e);
+            return;
+        }
+    };
+
+    rt.block_on(async {
+        while let Some((job, reply)) = jobs.recv().await {
+            let error = match job {
+                Job::Connect if controller.is_connected() => None,
+                Job::Connect => controller.connect().await.err().map(|e| e.to_string()),
+                Job::Command(command) => run_command(&mut controller, command).await,
+            };
+            connected.store(controller.is_connected(), Ordering::Relaxed);
+            reply.send(error).ok();
+        }
+
+        // release the socket before the runtime goes away
+        if let Err(e) = controller.disconnect().await {
+            nih_error!("Failed to disconnect from shared bulb: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn config(device_id: &str) -> BulbConfig {
+        BulbConfig::new(device_id, "0123456789abcdef", "127.0.0.1", "3.3").unwrap()
+    }
+
+    #[test]
+    fn instances_share_one_bulb() {
+        let first = SharedBulb::acquire(config("bf0123456789abcdef01")).unwrap();
+        let second = SharedBulb::acquire(config("bf0123456789abcdef01")).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn releasing_on_a_runtime_does_not_wait_for_the_thread() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let first = SharedBulb::acquire(config("bf0123456789abcdef02")).unwrap();
+
+        // the last instance lets go from inside a bulb thread's runtime
+        let started = Instant::now();
+        rt.block_on(async move { drop(first) });
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let second = SharedBulb::acquire(config("bf0123456789abcdef02")).unwrap();
+        assert!(rt.block_on(second.run(BulbCommand::SetHSV(361, 1000, 1000, true))).is_some());
+        assert!(REGISTRY.lock().unwrap().get("bf0123456789abcdef02").is_some_and(|bulb| bulb.strong_count() == 1));
+    }
+}
@@ -0,0 +1,95 @@
+//! SysEx messages for setting exact colors from hardware or scripts
+//!
+//! Messages use the non-commercial manufacturer ID `7D` followed by `4C`
+//! ("L") so other `7D` users are left alone. Values are 14 bit, sent as two
+//! data bytes with the high 7 bits first:
+//!
+//! ```text
+//! F0 7D 4C 01 hh hl sh sl bh bl F7    color: hue 0-360, saturation and brightness 0-1000
+//! F0 7D 4C 02 nn F7                   recall preset nn, counting from 1
+//! ```
+//!
+//! Anything else, including values out of range, is ignored.
+
+use crate::MAX_PRESETS;
+use nih_plug::prelude::*;
+
+const PREFIX: [u8; 3] = [0xf0, 0x7d, 0x4c];
+const END: u8 = 0xf7;
+
+const COLOR: u8 = 0x01;
+const RECALL: u8 = 0x02;
+
+/// The longest message, a color
+const MAX_LEN: usize = 11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulbSysEx {
+    /// In the ranges `BulbController::set_color` takes
+    Color { hue: u16, saturation: u16, brightness: u16 },
+    /// A preset position, 1 is the first preset
+    Recall(u8),
+}
+
+impl SysExMessage for BulbSysEx {
+    type Buffer = [u8; MAX_LEN];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        if buffer.get(..PREFIX.len())? != PREFIX {
+            return None;
+        }
+
+        // hosts may hand over more than one message, only the first is ours
+        let body = &buffer[PREFIX.len()..];
+        let message = match *body.first()? {
+            COLOR => {
+                let data = body.get(1..7)?;
+                if body.get(7) != Some(&END) {
+                    return None;
+                }
+                let value = |index: usize| {
+                    let (high, low) = (data[index * 2], data[index * 2 + 1]);
+                    (high < 0x80 && low < 0x80).then_some(((high as u16) << 7) | low as u16)
+                };
+                BulbSysEx::Color {
+                    hue: value(0).filter(|&hue| hue <= 360)?,
+                    saturation: value(1).filter(|&saturation| saturation <= 1000)?,
+                    brightness: value(2).filter(|&brightness| brightness <= 1000)?,
+                }
+            }
+            RECALL => {
+                let index = *body.get(1)?;
+                if body.get(2) != Some(&END) || !(1..=MAX_PRESETS).contains(&(index as usize)) {
+                    return None;
+                }
+                BulbSysEx::Recall(index)
+            }
+            _ => return None,
+        };
+
+        Some(message)
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        let mut buffer = [0; MAX_LEN];
+        buffer[..PREFIX.len()].copy_from_slice(&PREFIX);
+
+        let len = match self {
+            BulbSysEx::Color { hue, saturation, brightness } => {
+                buffer[3] = COLOR;
+                for (index, value) in [hue, saturation, brightness].into_iter().enumerate() {
+                    buffer[4 + index * 2] = (value >> 7) as u8 & 0x7f;
+                    buffer[5 + index * 2] = value as u8 & 0x7f;
+                }
+                10
+            }
+            BulbSysEx::Recall(index) => {
+                buffer[3] = RECALL;
+                buffer[4] = index & 0x7f;
+                5
+            }
+        };
+        buffer[len] = END;
+        (buffer, len + 1)
+    }
+}